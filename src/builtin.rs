@@ -0,0 +1,89 @@
+
+use std::collections::HashMap;
+
+use ::unicode_normalization::UnicodeNormalization;
+use ::unicode_normalization::char::canonical_combining_class;
+
+static GREEK: &'static [(&'static str, &'static str)] = &[
+    // lowercase greek characters
+    ("alpha", "α"), ("beta", "β"), ("gamma", "γ"), ("delta", "δ"), ("epsilon", "ϵ"), 
+    ("varepsilon", "ε"), ("zeta", "ζ"), ("eta", "η"), ("theta", "θ"), ("iota", "ι"), 
+    ("kappa", "κ"), ("lambda", "λ"), ("mu", "μ"), ("nu", "ν"), ("xi", "ξ"), ("omicron", "ο"), 
+    ("pi", "π"), ("rho", "ρ"), ("sigma", "σ"), ("varsigma", "ς"), ("tau", "τ"), 
+    ("upsilon", "υ"), ("phi", "ϕ"), ("varphi", "φ"), ("chi", "χ"), ("psi", "ψ"), 
+    ("omega", "ω"),
+
+    // uppercase greek characters
+    ("Alpha", "Α"), ("Beta", "Β"), ("Gamma", "Γ"), ("Delta", "Δ"), ("Epsilon", "Ε"), 
+    ("Zeta", "Ζ"), ("Eta", "Η"), ("Theta", "Θ"), ("Iota", "Ι"), ("Kappa", "Κ"), 
+    ("Lambda", "Λ"), ("Mu", "Μ"), ("Nu", "Ν"), ("Xi", "Ξ"), ("Omicron", "Ο"), ("Pi", "Π"), 
+    ("Rho", "Ρ"), ("Sigma", "Σ"), ("Tau", "Τ"), ("Upsilon", "Υ"), ("Phi", "Φ"), ("Chi", "Χ"), 
+    ("Psi", "Ψ"), ("Omega", "Ω")
+];
+
+/// LaTeX accent commands and the unicode combining character they correspond to.
+static ACCENTS: &'static [(&'static str, char)] = &[
+    ("'", '\u{0301}'), ("`", '\u{0300}'), ("^", '\u{0302}'), ("~", '\u{0303}'), 
+    ("\"", '\u{0308}'), ("=", '\u{0304}'), (".", '\u{0307}'), ("c", '\u{0327}')
+];
+
+fn to_map(table: &[(&str, &str)]) -> HashMap<String, String> {
+    table.iter().map(|&(k,v)| (k.to_string(), v.to_string())).collect()
+}
+
+/// Lower- and uppercase greek letters, e.g. `alpha` → `α` and `Omega` → `Ω`.
+pub fn greek_patterns() -> HashMap<String, String> {
+    to_map(GREEK)
+}
+
+/// Accent commands applied to a latin letter, e.g. `'e` and `'{e}` → `é` or `c c` → `ç`. 
+/// Only combinations with a precomposed unicode character (according to NFC) are included; 
+/// all other accent commands are left untouched.
+pub fn accent_patterns() -> HashMap<String, String> {
+    let mut patterns = HashMap::new();
+    for &(command, mark) in ACCENTS {
+        let letters = (b'a' .. b'z' + 1).chain(b'A' .. b'Z' + 1).map(|b| b as char);
+        for letter in letters {
+            let composed : String = [letter, mark].iter().cloned().nfc().collect();
+            if composed.chars().count() != 1 {
+                continue;
+            }
+            // Commands made of letters need a space to be separated from their argument
+            if command.bytes().all(|b| b.is_ascii_alphabetic()) {
+                patterns.insert(format!("{} {}", command, letter), composed.clone());
+            } else {
+                patterns.insert(format!("{}{}", command, letter), composed.clone());
+            }
+            patterns.insert(format!("{}{{{}}}", command, letter), composed);
+        }
+    }
+    patterns
+}
+
+/// Looks up a built-in pattern set by name.
+pub fn patterns(name: &str) -> Option<HashMap<String, String>> {
+    match name {
+        "greek" => Some(greek_patterns()),
+        "accents" => Some(accent_patterns()),
+        _ => None
+    }
+}
+
+/// ASCII approximation of a single character: the name of a greek letter (`α` → `alpha`) or a 
+/// latin letter without its accents (`é` → `e`).
+pub fn transliteration(c: char) -> Option<String> {
+    if c.is_ascii() {
+        return Some(c.to_string());
+    }
+    let mut buf = [0; 4];
+    let text : &str = c.encode_utf8(&mut buf);
+    if let Some(&(name, _)) = GREEK.iter().find(|&&(_, letter)| letter == text) {
+        return Some(name.to_string());
+    }
+    let base : String = text.nfd().filter(|&d| canonical_combining_class(d) == 0).collect();
+    if !base.is_empty() && base.is_ascii() {
+        Some(base)
+    } else {
+        None
+    }
+}
@@ -2,9 +2,10 @@
 use std::process::exit;
 use std::sync::{Once,ONCE_INIT};
 use std::io::{stderr,Write};
+use std::env;
 
-use log::LogLevel;
-use ::env_logger;
+use log::{LogLevel,LogLevelFilter};
+use ::env_logger::LogBuilder;
 
 use ::error::UniError;
 
@@ -13,12 +14,36 @@ pub const TO_UNI_VERSION : &'static str = env!("CARGO_PKG_VERSION");
 
 pub type UniResult<T> = Result<T, UniError>;
 
-/// Make sure errors are displayed in some form at the end of the program.
-pub fn handle_program_exit(result: UniResult<()>) {
+/// Single-line error report for consumption by other programs.
+#[derive(Serialize)]
+struct JsonError<'a> {
+  code_major: u8,
+  code_minor: u8,
+  error_code: u8,
+  kind: &'a str,
+  message: String
+}
+
+/// Make sure errors are displayed in some form at the end of the program. With `json_errors`, 
+/// the error is printed to stderr as a single line of JSON instead.
+pub fn handle_program_exit(result: UniResult<()>, json_errors: bool) {
   match result {
     Ok(_) => {
       exit(0);
     },
+    Err(ref e) if json_errors => {
+      let report = JsonError {
+        code_major: e.code_major(),
+        code_minor: e.code_minor(),
+        error_code: e.error_code(),
+        kind: e.kind(),
+        message: e.to_string()
+      };
+      if let Ok(line) = ::serde_json::to_string(&report) {
+        let _ = writeln!(&mut stderr(), "{}", line);
+      }
+      exit(e.error_code() as i32);
+    },
     Err(e) => {
         // We need erros to be shown to the user. If we can, we use the error logging mechanism.
         // Otherwise, we just print to stderr. 
@@ -37,9 +62,15 @@ pub fn handle_program_exit(result: UniResult<()>) {
 
 static PROGRESSD_INIT: Once = ONCE_INIT;
 
-/// Initialize subsystems required by to-uni.
-pub fn init() {
+/// Initialize subsystems required by to-uni. Logging defaults to `default_level`, but the 
+/// `RUST_LOG` environment variable still has the final say.
+pub fn init(default_level: LogLevelFilter) {
   PROGRESSD_INIT.call_once(|| {
-    env_logger::init().unwrap();
+    let mut builder = LogBuilder::new();
+    builder.filter(None, default_level);
+    if let Ok(spec) = env::var("RUST_LOG") {
+      builder.parse(&spec);
+    }
+    builder.init().unwrap();
   });
 }
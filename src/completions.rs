@@ -0,0 +1,136 @@
+//! Shell completion scripts. The commands and options are taken from the usage text, so the
+//! scripts stay in sync with the command line interface.
+
+use ::config::USAGE;
+
+/// Shells for which a completion script can be generated.
+pub const SHELLS: &'static [&'static str] = &["bash", "zsh", "fish"];
+
+/// An option from the usage text, e.g., `--config` (with a value) or `-q`.
+struct UsageOption {
+    name: String,
+    takes_value: bool
+}
+
+/// The completion script for `shell`, or `None` if the shell is not supported.
+pub fn script(shell: &str) -> Option<String> {
+    let commands = commands();
+    let options = options();
+    match shell {
+        "bash" => Some(bash(&commands, &options)),
+        "zsh" => Some(zsh(&commands, &options)),
+        "fish" => Some(fish(&commands, &options)),
+        _ => None
+    }
+}
+
+/// The usage patterns: the lines between `Usage:` and the next blank line. The description 
+/// above them mentions `to-uni` as well.
+fn usage_lines() -> Vec<&'static str> {
+    USAGE.lines()
+        .skip_while(|line| line.trim() != "Usage:")
+        .skip(1)
+        .take_while(|line| !line.trim().is_empty())
+        .collect()
+}
+
+/// Sub-commands like `restore`: the first word after `to-uni` in a usage line, unless it is an
+/// option or argument. An optional command like `[convert]` counts as well.
+fn commands() -> Vec<String> {
+    let mut commands : Vec<String> = usage_lines().into_iter()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace()
+                .map(|word| word.trim_matches(|c| c == '[' || c == ']'));
+            match (words.next(), words.next()) {
+                (Some("to-uni"), Some(word)) if word != "options" 
+                    && word.starts_with(|c: char| c.is_ascii_lowercase()) => Some(word.to_owned()),
+                _ => None
+            }
+        })
+        .collect();
+    commands.dedup();
+    commands
+}
+
+/// Options from the option descriptions. Each description starts with the names of the option,
+/// separated by spaces and followed by at least two spaces (or the end of the line).
+fn options() -> Vec<UsageOption> {
+    let mut options = Vec::new();
+    for line in USAGE.lines().filter(|line| line.starts_with("    -")) {
+        let names = line.trim().split("  ").next().unwrap_or("");
+        for name in names.split_whitespace() {
+            let mut parts = name.splitn(2, '=');
+            options.push(UsageOption {
+                name: parts.next().unwrap_or(name).to_owned(),
+                takes_value: parts.next().is_some()
+            });
+        }
+    }
+    options
+}
+
+fn option_names(options: &[UsageOption]) -> String {
+    options.iter().map(|option| option.name.as_str()).collect::<Vec<_>>().join(" ")
+}
+
+fn bash(commands: &[String], options: &[UsageOption]) -> String {
+    format!(r#"# bash completion for to-uni
+_to_uni() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}"
+    if [[ "$cur" == -* ]]; then
+        COMPREPLY=($(compgen -W "{options}" -- "$cur"))
+    elif [[ $COMP_CWORD -eq 1 ]]; then
+        COMPREPLY=($(compgen -W "{commands}" -- "$cur") $(compgen -f -- "$cur"))
+    else
+        COMPREPLY=($(compgen -f -- "$cur"))
+    fi
+}}
+complete -o filenames -F _to_uni to-uni
+"#, options = option_names(options), commands = commands.join(" "))
+}
+
+fn zsh(commands: &[String], options: &[UsageOption]) -> String {
+    format!(r#"#compdef to-uni
+
+_to_uni() {{
+    local -a options commands
+    options=({options})
+    commands=({commands})
+    if [[ $PREFIX == -* ]]; then
+        compadd -a options
+    else
+        (( CURRENT == 2 )) && compadd -a commands
+        _files
+    fi
+}}
+
+_to_uni "$@"
+"#, options = option_names(options), commands = commands.join(" "))
+}
+
+fn fish(commands: &[String], options: &[UsageOption]) -> String {
+    let mut script = String::from("# fish completion for to-uni\n");
+    script.push_str(&format!("complete -c to-uni -n __fish_use_subcommand -a '{}'\n",
+        commands.join(" ")));
+    for option in options {
+        let (kind, name) = if option.name.starts_with("--") {
+            ("-l", &option.name[2 ..])
+        } else {
+            ("-s", &option.name[1 ..])
+        };
+        script.push_str(&format!("complete -c to-uni {} {}{}\n", kind, name,
+            if option.takes_value { " -r" } else { "" }));
+    }
+    script
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_are_taken_from_the_usage_patterns() {
+        assert_eq!(commands(), vec!["restore", "list-patterns", "convert", "validate", "init", 
+            "completions", "list-codes"]);
+    }
+}
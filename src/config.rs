@@ -1,61 +1,522 @@
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::{PathBuf, Path};
-use std::io::{self,Read,Write, stdin, stdout};
+use std::io::{self,IsTerminal,Read,Write, stdin, stdout};
 use std::fs::{self, File};
 use std::env;
+use std::process;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use ::yaml::Yaml;
+use ::log::LogLevelFilter;
 
 use ::common::*;
 use ::error;
+use ::builtin;
+use ::conversion::{Marker, MatchMode};
+use ::regions::RegionSettings;
+use ::normalization::{LineEndings, Normalization};
+use ::translit::{MissingTranslit, Transliteration};
+use ::format::OutputFormat;
+use ::encoding_rs::{Encoding, UTF_8};
+use ::encoding::BomMode;
 
 pub static USAGE: &'static str ="
 to-uni is a program that scans for LaTeX-style escape sequences in its input and replaces 
 them with their unicode counterpart.
 
 Usage:
-    to-uni [options] (<input>|[-]) [<output>|--stdout]
+    to-uni restore [options] [-q | -v...] <file>...
+    to-uni list-patterns [options] [-q | -v...] [--pattern=MAPPING]... [--only=KEY]... [--exclude=KEY]... [--json]
+    to-uni [convert] [options] [-q | -v...] [--pattern=MAPPING]... [--only=KEY]... [--exclude=KEY]... --in-place <file>...
+    to-uni [convert] [options] [-q | -v...] [--pattern=MAPPING]... [--only=KEY]... [--exclude=KEY]... --files-from=LIST
+    to-uni [convert] [options] [-q | -v...] [--pattern=MAPPING]... [--only=KEY]... [--exclude=KEY]... --output-dir=DIR <file>...
+    to-uni [convert] [options] [-q | -v...] [--pattern=MAPPING]... [--only=KEY]... [--exclude=KEY]... (<input>|[-]) [<output>|--stdout]
+    to-uni validate [options] [-q | -v...]
+    to-uni init [-q | -v...] [--force] [<path>]
+    to-uni completions <shell>
+    to-uni list-codes [--json]
     to-uni --version
     to-uni -h | --help
 
+Commands:
+    convert                     Convert the input. This is the default, so the command can be 
+                                left out.
+    restore                     Undo an in-place conversion by moving the backup (e.g., 
+                                <file>.bak) back onto <file>.
+    list-patterns               Print the patterns a conversion would use (after merging 
+                                configuration files, --pattern, --only and --exclude), sorted 
+                                by key. The configuration file is searched for starting at the 
+                                current directory.
+    validate                    Load the configuration like a conversion would and report any 
+                                problems with it, without converting anything. Use --config to 
+                                name the file to check.
+    init                        Write a starter configuration file to <path> (a file or a 
+                                directory, the current directory by default).
+    completions                 Print a completion script for <shell> (bash, zsh or fish) to 
+                                standard output.
+    list-codes                  Print the exit codes to-uni uses and what they mean.
+
 Options:
     -h --help                   Show this screen
     --version                   Show the version and exit
+    --json-errors               Report a fatal error as a single line of JSON on standard error.
+    -q --quiet                  Only log errors
+    -v --verbose                Log more details. Repeat for even more (-vv: debug, -vvv: trace). 
+                                The RUST_LOG environment variable overrides this.
+    --json                      With list-patterns, print the patterns as a JSON object. With 
+                                list-codes, print the codes as a JSON array.
+    --force                     Overwrite an existing <output> file (in-place conversions 
+                                have backups instead). With init, overwrite an existing 
+                                configuration file.
+    --stdin-name=PATH           The file that standard input stands for, e.g., the file an 
+                                editor is converting. The configuration file is searched for 
+                                starting at its directory, and an output directory is 
+                                completed with its name.
     --stdout                    Write converted stream to standard output
+    --in-place                  Convert each of the given files in place. The configuration file 
+                                is searched for starting at the directory of the first file.
+    --files-from=LIST           Convert the files named in LIST (one per line, - for standard 
+                                input) in place, like --in-place.
+    --output-dir=DIR            Write the converted copy of each file to DIR (under the same 
+                                file name), leaving the originals alone. DIR is created if 
+                                necessary.
+    --output-template=TEMPLATE  Name of the converted files in the output directory (of 
+                                --output-dir or given as <output>), e.g., {stem}.uni.{ext}. 
+                                {name} is the file name of the input, {stem} the name without 
+                                extension, {ext} the extension and {n} the number of the input 
+                                (starting at 1).
+    -0 --null                   The names in the --files-from list are separated by NUL 
+                                characters (e.g., from find -print0) instead of newlines.
+    --jobs=N                    Number of files to convert in parallel. [default: 1]
+    --no-follow-symlinks        Refuse to convert symbolic links in place. By default, the file 
+                                a link points to is converted and the link is left alone.
+    --tmp-dir=DIR               Directory for the temp files of in-place conversions. By 
+                                default, the temp file is written next to the original, so it 
+                                can be moved into place atomically. If DIR is on a different 
+                                file system, the result is copied over the original instead.
+    --keep-temp-files           Don't remove the temp file of an in-place conversion that is 
+                                interrupted (Ctrl-C), e.g., to inspect the partial output.
     --no-backup -B              When doing an in-place conversion, don't create a backup of 
                                 the original
-    --config=CONFIG             Specific configuration file or search origin. 
-                                By default, to-uni uses the directory of the input file as a 
+    --backup-suffix=SUFFIX      Appended to the file name of the original to get the name of the 
+                                backup. [default: .bak]
+    --backup-keep=N             Keep the last N backups of each file, numbered from the newest 
+                                (<file>.bak.1) to the oldest (<file>.bak.N). [default: 1]
+    --backup-dir=DIR            Put backups into DIR instead of next to the original. The path 
+                                of the original relative to the current directory is mirrored 
+                                below DIR (absolute for files outside the current directory).
+    --config=CONFIG             Specific configuration file or search origin (- for standard 
+                                input, the input then needs to be a file). 
+                                Defaults to the environment variable TO_UNI_CONFIG. Without 
+                                either, to-uni uses the directory of the input file as a 
                                 starting point and searches upwards in the file system hierarchy
                                 until CFGNAME is found.
-    --config-name=CFGNAME       Name of the to-uni configuration file (YAML) [default: to-uni.yml]
+    --config-root-marker=MARKER  
+                                Stop searching for the configuration file upwards after the 
+                                first directory that contains MARKER (e.g., .git).
+    --merge-configs             Merge all configuration files found searching upwards instead 
+                                of using only the first one. Closer files take precedence.
+    --show-config               Print the configuration files in use, the pattern markers and 
+                                the number of patterns to standard error before converting. 
+                                Combined with --dry-run, stop there.
+    --no-config                 Don't look for a configuration file. Patterns then need to come 
+                                from somewhere else, e.g., --builtin.
+    --only=KEY                  Only replace the patterns with these keys. Can be repeated. 
+                                Keys can contain the wildcards * and ?, e.g., --only 'arrow*'.
+    --exclude=KEY               Don't replace the patterns with these keys. Can be repeated and 
+                                contain wildcards like --only.
+    --strict-conflicts          Fail if configuration files define the same key with different 
+                                values instead of just warning about it.
+    --strict                    Fail if a key is a prefix of another key (e.g., alpha and 
+                                alphabet) instead of just warning about it.
+    --category=NAMES            Only load the patterns of these categories (comma-separated) 
+                                from the configuration file. Patterns outside of any category 
+                                are always loaded.
+    --pattern=MAPPING           Additional pattern of the form KEY=VALUE. Can be repeated. 
+                                Takes precedence over the configuration file.
+    --config-name=CFGNAME       Name of the to-uni configuration file (YAML, or TOML if the name 
+                                ends in .toml). Several comma-separated names are tried in 
+                                order in each directory. [default: to-uni.yml,to-uni.toml]
+    --config-doc=N              Use the Nth document (counting from 0) of YAML configuration 
+                                files with several documents separated by ---. Defaults to the 
+                                first one.
+    --check                     Don't write anything. Instead, fail if the input contains 
+                                anything that would be replaced.
+    --diff                      Don't write anything. Instead, print a unified diff of the 
+                                changes to standard output.
+    --dry-run                   Perform the conversion, but don't write any output.
+    --max-replacements=N        Stop replacing after N replacements (over all inputs). The 
+                                rest of the input is passed through unchanged.
+    --interactive               Ask before each replacement. Answer y (yes), n (no), a (all: 
+                                stop asking) or q (quit, leaving the current file unchanged). 
+                                The answers are read from standard input, so the input needs to 
+                                be a file.
+    --watch                     After converting, keep watching the input files and convert 
+                                them again whenever they change. Stop with Ctrl-C.
+    --stats                     Print a summary (patterns, files, replacements, bytes read and 
+                                written, elapsed time) to standard error.
+    --progress                  Show how much of each input has been converted on standard 
+                                error, if it is a terminal. Only for output to files, and not 
+                                with --jobs or --mmap.
+    --force-progress            Show the progress even if standard error is not a terminal.
+    --measure                   Print the time spent building the matching automaton and 
+                                converting the inputs (with the throughput) to standard error.
+    --color=WHEN                Highlight the replacements in the output: auto (if standard 
+                                output is a terminal), always or never. Only applies to output 
+                                to standard output, files never contain color codes. 
+                                [default: auto]
+    --trace                     Print each replacement with its position in the input (line, 
+                                column and byte offset) to standard error.
+    --report=FILE               Write the number of replacements of each pattern (including 
+                                the unused ones) to FILE as a JSON object, summed over all 
+                                inputs.
+    --print0-report=FILE        Write a record of each replacement to FILE: the name of the 
+                                input, the byte offset in it, the pattern and the replacement, 
+                                each followed by a NUL byte.
+    --report-fd=N               Write the records of --print0-report to the already open file 
+                                descriptor N (e.g., 3 with 3>records in the shell) instead.
+    --stats-json                Print a summary (patterns, replacements, bytes read and written, 
+                                elapsed time and the same per file) to standard output as a 
+                                single JSON object.
+    --stats-file=FILE           Write the JSON summary of --stats-json to FILE instead.
+    --report-unmatched          Print escape sequences that were not replaced (with their number 
+                                of occurrences) to standard error.
+    --mmap                      Memory-map the input file and convert it in a single pass. 
+                                Faster for large files. Has no effect on standard input.
+    --gzip                      Decompress the input and compress the output with gzip. Implied 
+                                for input files ending in .gz.
+    --buffer-size=BYTES         Size of the buffer used for streaming the input. Needs to be at 
+                                least as large as the longest pattern. [default: 512]
+    --match-mode=MODE           When to replace an occurrence: raw (always), word-boundary 
+                                (only if no letter follows a pattern ending in a letter) or 
+                                braces (only if followed by {}, which is removed as well). 
+                                Overrides match_mode from the configuration file. Default: raw
+    --consume-trailing-space    Remove a single space following each replaced pattern, so that 
+                                \\alpha x becomes αx.
+    --input-encoding=ENC        Character encoding of the input, e.g., latin1 or utf-16le. 
+                                Default: UTF-8
+    --check-utf8                Fail if the input is not valid UTF-8 instead of passing the 
+                                invalid bytes through unchanged.
+    --output-encoding=ENC       Character encoding of the output, e.g., latin1. Characters 
+                                that the encoding lacks are an error. Default: UTF-8
+    --line-endings=STYLE        Line endings of the output: preserve (whatever the input uses), 
+                                lf or crlf. [default: preserve]
+    --bom=MODE                  What to do with a byte order mark at the start of the input: 
+                                keep it, strip it or add one to the output. [default: keep]
+    --normalize=FORM            Normalize the output to Unicode normalization form nfc 
+                                (precomposed) or nfd (decomposed). By default, the text is 
+                                written as is.
+    --output-format=FORMAT      How to write the replacements: unicode, ncr (numeric character 
+                                references like &#x3B1;), html-entity (named entities like 
+                                &alpha; where possible, ncr otherwise), rust-escape (\\u{3B1}) 
+                                or json-escape (\\u03b1). [default: unicode]
+    --translit                  Write an ASCII approximation of each replacement instead (é 
+                                becomes e, α becomes alpha). The translit section of the 
+                                configuration file can add to or override the built-in table.
+    --translit-missing=ACTION   What to do with characters that have no ASCII approximation: 
+                                pass (keep them) or error. [default: pass]
+    --math-only                 Only convert patterns in math mode: $...$, $$...$$, \\(...\\) 
+                                and \\[...\\].
+    --respect-comments          Leave LaTeX comments (after an unescaped %) alone.
+    --respect-verbatim          Leave verbatim environments and \\verb alone.
+    --verbatim-environments=ENVS  
+                                Comma-separated names of the environments skipped by 
+                                --respect-verbatim. [default: verbatim,lstlisting]
+    --reverse                   Convert unicode characters back into their escape sequences. 
+                                Requires every replacement in the configuration to be unique.
+    --builtin=SETS              Use built-in pattern sets (comma-separated). Entries from a 
+                                configuration file (which becomes optional) take precedence. 
+                                Available sets: greek, accents
 
 ";
 
+/// Environment variable with the path of the configuration file (or search origin). Used unless 
+/// --config is given.
+pub static CONFIG_ENV_VAR: &'static str = "TO_UNI_CONFIG";
+
+/// Stands for the configuration read from standard input (`--config -`) in messages.
+static STDIN_CONFIG_NAME: &'static str = "<stdin>";
+
+/// Name of the configuration file written by init if it is given a directory.
+pub static DEFAULT_CONFIG_NAME: &'static str = "to-uni.yml";
+
+/// Starter configuration written by init.
+pub static CONFIG_TEMPLATE: &'static str = r#"---
+# Configuration for to-uni. Each entry under `patterns` replaces an escape sequence (the key 
+# with a leading backslash, e.g., \alpha) with its value.
+
+# Keys start with a backslash unless you set a different prefix (and optionally a suffix):
+# prefix: "\\"
+
+# When to replace an occurrence: raw (always), word-boundary or braces.
+# match_mode: raw
+
+patterns:
+    # Greek letters
+    alpha: "α"
+    beta: "β"
+    gamma: "γ"
+    delta: "δ"
+    lambda: "λ"
+    pi: "π"
+
+    # Arrows. A list makes the other entries aliases: \rightarrow is replaced just like \to.
+    to: ["→", "rightarrow"]
+    gets: ["←", "leftarrow"]
+    Rightarrow: "⇒"
+    Leftrightarrow: "⇔"
+
+    # Relations
+    leq: "≤"
+    geq: "≥"
+"#;
+
 #[derive(Debug,Deserialize)]
 #[allow(non_snake_case)]
 pub struct Args {
+    cmd_restore: bool,
+    cmd_convert: bool,
+    cmd_list_patterns: bool,
+    flag_json: bool,
+    cmd_validate: bool,
+    cmd_init: bool,
+    flag_force: bool,
+    arg_path: Option<String>,
+    cmd_completions: bool,
+    arg_shell: Option<String>,
+    cmd_list_codes: bool,
+    flag_quiet: bool,
+    flag_verbose: usize,
+    flag_json_errors: bool,
     arg_input: Option<String>,
+    flag_stdin_name: Option<String>,
     arg_output: Option<String>,
+    arg_file: Vec<String>,
+    flag_in_place: bool,
+    flag_files_from: Option<String>,
+    flag_output_dir: Option<String>,
+    flag_output_template: Option<String>,
+    flag_null: bool,
+    flag_jobs: usize,
     flag_config: Option<String>,
     flag_config_name: String,
+    flag_config_doc: Option<usize>,
     flag_stdout: bool,
-    flag_no_backup: bool
+    flag_no_backup: bool,
+    flag_keep_temp_files: bool,
+    flag_tmp_dir: Option<String>,
+    flag_no_follow_symlinks: bool,
+    flag_backup_suffix: String,
+    flag_backup_dir: Option<String>,
+    flag_backup_keep: usize,
+    flag_reverse: bool,
+    flag_builtin: Option<String>,
+    flag_no_config: bool,
+    flag_show_config: bool,
+    flag_merge_configs: bool,
+    flag_config_root_marker: Option<String>,
+    flag_pattern: Vec<String>,
+    flag_only: Vec<String>,
+    flag_exclude: Vec<String>,
+    flag_check: bool,
+    flag_diff: bool,
+    flag_dry_run: bool,
+    flag_watch: bool,
+    flag_interactive: bool,
+    flag_max_replacements: Option<usize>,
+    flag_report_unmatched: bool,
+    flag_report: Option<String>,
+    flag_print0_report: Option<String>,
+    flag_report_fd: Option<i32>,
+    flag_trace: bool,
+    flag_color: String,
+    flag_stats: bool,
+    flag_measure: bool,
+    flag_progress: bool,
+    flag_force_progress: bool,
+    flag_stats_json: bool,
+    flag_stats_file: Option<String>,
+    flag_match_mode: Option<String>,
+    flag_consume_trailing_space: bool,
+    flag_respect_comments: bool,
+    flag_math_only: bool,
+    flag_normalize: Option<String>,
+    flag_input_encoding: Option<String>,
+    flag_check_utf8: bool,
+    flag_output_encoding: Option<String>,
+    flag_bom: String,
+    flag_line_endings: String,
+    flag_output_format: String,
+    flag_translit: bool,
+    flag_category: Option<String>,
+    flag_strict_conflicts: bool,
+    flag_strict: bool,
+    flag_translit_missing: String,
+    flag_respect_verbatim: bool,
+    flag_verbatim_environments: String,
+    flag_mmap: bool,
+    flag_gzip: bool,
+    flag_buffer_size: usize
+}
+
+impl Args {
+    /// Whether fatal errors should be reported as JSON.
+    pub fn json_errors(&self) -> bool {
+        self.flag_json_errors
+    }
+
+    /// Log level requested via --quiet and --verbose.
+    pub fn log_level(&self) -> LogLevelFilter {
+        if self.flag_quiet {
+            return LogLevelFilter::Error;
+        }
+        match self.flag_verbose {
+            0 => LogLevelFilter::Warn,
+            1 => LogLevelFilter::Info,
+            2 => LogLevelFilter::Debug,
+            _ => LogLevelFilter::Trace
+        }
+    }
+}
+
+/// Expands a leading `~` (or `~user`) and `$VAR`/`${VAR}` references in a path argument. 
+/// Paths without either are taken literally.
+fn expand_path(raw_path: &str) -> UniResult<PathBuf> {
+    if !raw_path.starts_with('~') && !raw_path.contains('$') {
+        return Ok(PathBuf::from(raw_path));
+    }
+    let expanded = expand_vars(&expand_tilde(raw_path)?, raw_path)?;
+    debug!("Expanded path {} to {}", raw_path, expanded);
+    Ok(PathBuf::from(expanded))
+}
+
+fn expand_tilde(raw_path: &str) -> UniResult<String> {
+    if !raw_path.starts_with('~') {
+        return Ok(raw_path.to_owned());
+    }
+    let (user, rest) = match raw_path.find(::std::path::is_separator) {
+        Some(end) => (&raw_path[1..end], &raw_path[end..]),
+        None => (&raw_path[1..], "")
+    };
+    let home = if user.is_empty() { current_home_dir() } else { user_home_dir(user) };
+    match home {
+        Some(home) => Ok(format!("{}{}", home, rest)),
+        None => Err(error::usage(format!("Cannot determine the home directory for ~{} in path {}", 
+            user, raw_path)))
+    }
+}
+
+fn current_home_dir() -> Option<String> {
+    env::var("HOME").or_else(|_| env::var("USERPROFILE")).ok()
+}
+
+/// Looks up the home directory of `user` in the password database.
+#[cfg(unix)]
+fn user_home_dir(user: &str) -> Option<String> {
+    let mut passwd = String::new();
+    File::open("/etc/passwd").and_then(|mut f| f.read_to_string(&mut passwd)).ok()?;
+    passwd.lines()
+        .map(|line| line.split(':').collect::<Vec<_>>())
+        .find(|fields| fields.len() >= 6 && fields[0] == user)
+        .map(|fields| fields[5].to_owned())
+}
+
+#[cfg(not(unix))]
+fn user_home_dir(_user: &str) -> Option<String> {
+    None
+}
+
+fn expand_vars(path: &str, raw_path: &str) -> UniResult<String> {
+    let mut expanded = String::with_capacity(path.len());
+    let mut rest = path;
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        let reference = &rest[start + 1..];
+        let (name, remainder) = if reference.starts_with('{') {
+            match reference.find('}') {
+                Some(end) => (&reference[1..end], &reference[end + 1..]),
+                None => return Err(error::usage(format!(
+                    "Unterminated variable reference in path {}", raw_path)))
+            }
+        } else {
+            let end = reference.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(reference.len());
+            (&reference[..end], &reference[end..])
+        };
+
+        if name.is_empty() {
+            // A lone `$` is not a reference
+            expanded.push('$');
+        } else {
+            let value = env::var(name).map_err(|_| error::usage(format!(
+                "Environment variable {} used in path {} is not set.", name, raw_path)))?;
+            expanded.push_str(&value);
+        }
+        rest = remainder;
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Whether `text` matches `glob`, where `*` stands for any number of characters and `?` for a 
+/// single one.
+fn glob_matches(glob: &str, text: &str) -> bool {
+    let glob : Vec<char> = glob.chars().collect();
+    let text : Vec<char> = text.chars().collect();
+    // Position after the last `*` in the glob and the text position it currently matches up to
+    let mut backtrack : Option<(usize, usize)> = None;
+    let (mut gi, mut ti) = (0, 0);
+    while ti < text.len() {
+        match glob.get(gi) {
+            Some(&'*') => {
+                backtrack = Some((gi + 1, ti));
+                gi += 1;
+            },
+            Some(&c) if c == '?' || c == text[ti] => {
+                gi += 1;
+                ti += 1;
+            },
+            _ => match backtrack {
+                // Let the last `*` swallow one more character
+                Some((star_gi, star_ti)) => {
+                    backtrack = Some((star_gi, star_ti + 1));
+                    gi = star_gi;
+                    ti = star_ti + 1;
+                },
+                None => return false
+            }
+        }
+    }
+    glob[gi ..].iter().all(|&c| c == '*')
 }
 
 #[derive(Debug)]
 pub enum Input {
     /// Source file
     File(PathBuf),
-    /// Stdin
-    Stdin
+    /// Stdin, with the file name it stands for (--stdin-name), if any
+    Stdin(Option<PathBuf>)
 }
 
 impl Input {
+    /// Human readable name of the input for messages.
+    pub fn name(&self) -> String {
+        match *self {
+            Input::Stdin(Some(ref path)) => path.to_string_lossy().into_owned(),
+            Input::Stdin(None) => "<stdin>".to_string(),
+            Input::File(ref path) => path.to_string_lossy().into_owned()
+        }
+    }
+
     pub fn directory(&self) -> UniResult<PathBuf> {
         match *self {
-            Input::Stdin => Ok(env::current_dir()?),
+            Input::Stdin(Some(ref path)) => match path.parent() {
+                Some(base) if !base.as_os_str().is_empty() => Ok(base.to_path_buf()),
+                _ => Ok(env::current_dir()?)
+            },
+            Input::Stdin(None) => Ok(env::current_dir()?),
             Input::File(ref buf) => {
                 let base = try_!(buf.parent().ok_or("File does not have a parent directory."), 
                     ::error::code::internal::MISC);
@@ -66,7 +527,7 @@ impl Input {
 
     pub fn open(&self) -> UniResult<Box<Read>> {
         Ok(match *self {
-            Input::Stdin => Box::new(stdin()),
+            Input::Stdin(_) => Box::new(stdin()),
             Input::File(ref path) => 
                 Box::new(try_!(fs::File::open(path), 
                     path.to_string_lossy().into_owned(), ::error::code::fsio::INPUT))
@@ -75,12 +536,21 @@ impl Input {
 
     pub fn from_args(args: &Args) -> UniResult<Input> {
         if let Some(ref raw_input_path) = args.arg_input {
-            let input_path = PathBuf::from(raw_input_path);
+            if args.flag_stdin_name.is_some() {
+                return Err(error::usage(
+                    "--stdin-name only applies when reading from standard input.".to_owned())
+                    .with_minor(error::code::usage::CONFLICTING_OPTIONS));
+            }
+            let input_path = expand_path(raw_input_path)?;
             Input::verify_input_path(&input_path)?;
             Ok(Input::File(input_path))
         }
         else {
-            Ok(Input::Stdin)
+            let name = match args.flag_stdin_name {
+                Some(ref raw_name) => Some(expand_path(raw_name)?),
+                None => None
+            };
+            Ok(Input::Stdin(name))
         }
     }
 
@@ -96,74 +566,357 @@ impl Input {
 
 #[derive(Debug)]
 pub enum Output {
-    /// Destination file, Temporary file, create backup
-    InPlace(PathBuf, PathBuf, bool),
-    /// Destination file
-    OtherFile(PathBuf),
+    /// Destination file, Temporary file, backup (if any)
+    InPlace(PathBuf, PathBuf, Option<Backup>),
+    /// Destination file, whether an existing file may be overwritten (--force)
+    OtherFile(PathBuf, bool),
     /// Stdout
     Stdout
 }
 
+/// How the backup of an in-place conversion is named.
+#[derive(Debug, Clone)]
+pub struct Backup {
+    /// Appended to the file name of the original
+    suffix: String,
+    /// Directory collecting all backups. Backups are placed next to the original if absent.
+    dir: Option<PathBuf>,
+    /// Number of backups kept per file. With more than one, the backups are numbered, the 
+    /// newest one being `.1`.
+    keep: usize
+}
+
+impl Backup {
+    pub fn new(suffix: String, dir: Option<PathBuf>) -> UniResult<Backup> {
+        if suffix.is_empty() {
+            return Err(error::usage("The backup suffix must not be empty.".to_owned()));
+        }
+        if suffix.chars().any(::std::path::is_separator) {
+            return Err(error::usage(format!(
+                "The backup suffix must not contain path separators: {}", suffix)));
+        }
+        Ok(Backup { suffix, dir, keep: 1 })
+    }
+
+    /// Keeps the last `keep` backups of each file instead of just one.
+    pub fn with_keep(self, keep: usize) -> UniResult<Backup> {
+        if keep == 0 {
+            return Err(error::usage(concat!("--backup-keep needs to be at least 1. ",
+                "Use --no-backup to skip the backup.").to_owned()));
+        }
+        Ok(Backup { keep, .. self })
+    }
+
+    fn from_args_always(args: &Args) -> UniResult<Backup> {
+        Backup::new(args.flag_backup_suffix.clone(), args.flag_backup_dir.as_ref().map(PathBuf::from))
+            .and_then(|backup| backup.with_keep(args.flag_backup_keep))
+    }
+
+    /// The backup configuration for in-place conversions, `None` if no backup is wanted.
+    fn from_args(args: &Args) -> UniResult<Option<Backup>> {
+        if args.flag_no_backup {
+            Ok(None)
+        } else {
+            Backup::from_args_always(args).map(Some)
+        }
+    }
+
+    /// Path of the (newest) backup of `dest_path`: `<name><suffix>` in the same directory or, 
+    /// with a backup directory, at the mirrored location below that directory. Files with the 
+    /// same name from different directories thus never share a backup.
+    fn path(&self, dest_path: &Path) -> UniResult<PathBuf> {
+        self.numbered_path(dest_path, 1)
+    }
+
+    /// Path of the `number`th newest backup of `dest_path`, starting at 1. Backups are only 
+    /// numbered if more than one is kept: `<name><suffix>.<number>`.
+    fn numbered_path(&self, dest_path: &Path, number: usize) -> UniResult<PathBuf> {
+        let mut file_name : ::std::ffi::OsString = try_!(dest_path.file_name()
+            .ok_or("Destination path does not have file name component."), 
+            ::error::code::internal::MISC).to_os_string();
+        file_name.push(&self.suffix);
+        if self.keep > 1 {
+            file_name.push(format!(".{}", number));
+        }
+        match self.dir {
+            None => Ok(dest_path.with_file_name(file_name)),
+            Some(ref dir) => 
+                Ok(dir.join(Backup::mirrored_path(dest_path)?).with_file_name(file_name))
+        }
+    }
+
+    /// Makes room for a new backup of `dest_path` by renaming the existing numbered backups: 
+    /// `.1` becomes `.2` and so on, the oldest one is overwritten. Each rename is atomic.
+    fn rotate(&self, dest_path: &Path) -> UniResult<()> {
+        for number in (1 .. self.keep).rev() {
+            let older_path = self.numbered_path(dest_path, number)?;
+            if !older_path.exists() {
+                continue;
+            }
+            let next_path = self.numbered_path(dest_path, number + 1)?;
+            debug!("Rotating backup {} to {}", older_path.display(), next_path.display());
+            try_!(fs::rename(&older_path, &next_path), older_path.to_string_lossy().into_owned(), 
+                ::error::code::fsio::OUTPUT_BACKUP);
+        }
+        Ok(())
+    }
+
+    /// Relative path under which the backup of `dest_path` is stored in the backup directory. 
+    /// The file is resolved (following symlinks) so that different ways of naming the same file 
+    /// agree. Files inside the current directory keep their relative path, all others use their 
+    /// absolute path without the root.
+    fn mirrored_path(dest_path: &Path) -> UniResult<PathBuf> {
+        let dest_path = try_!(fs::canonicalize(dest_path), 
+            dest_path.to_string_lossy().into_owned(), ::error::code::fsio::OUTPUT_BACKUP);
+        let current_dir = try_!(env::current_dir().and_then(fs::canonicalize), 
+            ".".to_owned(), ::error::code::fsio::OUTPUT_BACKUP);
+        let relative = dest_path.strip_prefix(&current_dir).unwrap_or(dest_path.as_path());
+        Ok(relative.components().filter_map(|component| match component {
+            ::std::path::Component::Normal(part) => Some(part),
+            _ => None
+        }).collect())
+    }
+}
+
+/// Temp files of in-place conversions that are currently being written, so that they can be 
+/// removed when the process is interrupted.
+static TEMP_FILES: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Number of temp files named in a --tmp-dir so far, to keep the names apart.
+static TEMP_FILE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
 impl Output {
-    fn open_path(path: &PathBuf) -> UniResult<Box<Write>> {
-        Ok(Box::new(try_!(fs::File::create(path), 
-                    path.to_string_lossy().into_owned(), ::error::code::fsio::OUTPUT)))
+    fn open_path(path: &PathBuf, overwrite: bool) -> UniResult<Box<Write>> {
+        // The directory exists unless it is an --output-dir that is used for the first time
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            try_!(fs::create_dir_all(dir), dir.to_string_lossy().into_owned(), 
+                ::error::code::fsio::OUTPUT);
+        }
+        // Without overwrite, the file is only created if it doesn't exist, in the same step. It 
+        // might have been created since `check_overwrite`.
+        let opened = fs::OpenOptions::new().write(true).create(overwrite).truncate(overwrite)
+            .create_new(!overwrite).open(path);
+        match opened {
+            Ok(file) => Ok(Box::new(file)),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => 
+                Err(Output::exists_error(path)),
+            Err(e) => Err(from_!(e, path.to_string_lossy().into_owned(), 
+                ::error::code::fsio::OUTPUT))
+        }
     }
 
     pub fn open(&self) -> UniResult<Box<Write>> {
         match *self {
-            Output::InPlace(_,ref tmp_path, _) => Output::open_path(tmp_path),
-            Output::OtherFile(ref path) => Output::open_path(path),
+            Output::InPlace(_,ref tmp_path, _) => {
+                // Tracked before it is created, so there is no moment where it could be missed
+                Output::track_temp_file(tmp_path);
+                Output::open_path(tmp_path, true).map_err(|e| {
+                    Output::untrack_temp_file(tmp_path);
+                    e
+                })
+            },
+            Output::OtherFile(ref path, overwrite) => Output::open_path(path, overwrite),
             Output::Stdout => Ok(Box::new(stdout()))
         }
     }
 
     /// Closes stream and performs cleanup work. Expects to be returned the stream that was 
-    /// opened before.
+    /// opened before. If closing fails, the temporary file of an in-place conversion is removed 
+    /// just like with `abort`.
     pub fn close(&self, mut file: Box<Write>) -> UniResult<()> {
         // Close the stream before we perform cleanup operations
-        file.flush()?;
+        if let Err(e) = file.flush() {
+            self.abort(file);
+            return Err(e.into());
+        }
         ::std::mem::drop(file);
 
         match *self {
-            Output::Stdout | Output::OtherFile(_) => (),
-            Output::InPlace(ref dest_path, ref tmp_path, backup) =>
-                Output::close_in_place(dest_path, tmp_path, backup)?
+            Output::Stdout | Output::OtherFile(..) => (),
+            Output::InPlace(ref dest_path, ref tmp_path, ref backup) => {
+                let result = Output::close_in_place(dest_path, tmp_path, backup.as_ref());
+                if result.is_err() {
+                    Output::remove_temp_file(tmp_path);
+                }
+                Output::untrack_temp_file(tmp_path);
+                result?
+            }
         }
 
         Ok(())
     }
 
-    fn close_in_place(dest_path: &PathBuf, tmp_path: &PathBuf, backup: bool) -> UniResult<()> {
-        if backup {
-            let mut backup_path = dest_path.clone();
-            let mut file_name : ::std::ffi::OsString = try_!(backup_path.file_name()
-                .ok_or("Destination path does not have file name component."), 
-                ::error::code::internal::MISC).to_os_string();
-            file_name.push(".bak");
-            backup_path.set_file_name(file_name);
-            info!("Backup path: {}", backup_path.display());
+    /// Discards the stream after a failed conversion (or one that didn't change anything). For 
+    /// in-place conversions, the temporary file is removed so that the original file is the only 
+    /// thing left behind. 
+    pub fn abort(&self, file: Box<Write>) {
+        ::std::mem::drop(file);
 
-            // Perform backup via an atomic replacement operation. 
-            // Existing file silently overwritten
-            debug!("Creating backup of {} as {} (overwriting any existing files)", 
-                dest_path.display(), backup_path.display());
-            try_!(::atomicwrites::replace_atomic(dest_path, &backup_path), 
-                dest_path.to_string_lossy().into_owned(), 
-                ::error::code::fsio::OUTPUT_BACKUP);
+        if let Output::InPlace(_, ref tmp_path, _) = *self {
+            Output::remove_temp_file(tmp_path);
+            Output::untrack_temp_file(tmp_path);
+        }
+    }
+
+    fn remove_temp_file(tmp_path: &Path) {
+        debug!("Removing temp output file {}.", tmp_path.display());
+        if let Err(e) = fs::remove_file(tmp_path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                warn!("Failed to remove temp output file {}: {}", tmp_path.display(), e);
+            }
+        }
+    }
+
+    fn track_temp_file(tmp_path: &Path) {
+        if let Ok(mut temp_files) = TEMP_FILES.lock() {
+            temp_files.push(tmp_path.to_path_buf());
+        }
+    }
+
+    fn untrack_temp_file(tmp_path: &Path) {
+        if let Ok(mut temp_files) = TEMP_FILES.lock() {
+            temp_files.retain(|path| path != tmp_path);
+        }
+    }
+
+    /// Removes the temp files of all in-place conversions in progress. Meant for an interrupted 
+    /// process; the conversions must not continue afterwards.
+    pub fn remove_temp_files() {
+        if let Ok(mut temp_files) = TEMP_FILES.lock() {
+            for tmp_path in temp_files.drain(..) {
+                debug!("Removing temp output file {} of interrupted conversion.", 
+                    tmp_path.display());
+                let _ = fs::remove_file(&tmp_path);
+            }
+        }
+    }
+
+    /// Swaps the fully written temp file into place. The operations are ordered such that the 
+    /// destination keeps its original content until the very last step: the backup is a copy 
+    /// that is moved into place atomically. A failure at any point leaves the original file 
+    /// and the (old or new) backup intact.
+    fn close_in_place(dest_path: &PathBuf, tmp_path: &PathBuf, backup: Option<&Backup>) 
+            -> UniResult<()> {
+        let tmp_stat = try_!(fs::metadata(tmp_path), tmp_path.to_string_lossy().into_owned(),
+            ::error::code::fsio::OUTPUT);
+        if !tmp_stat.is_file() {
+            return Err(from_!(format!("Temp output file {} has disappeared.", tmp_path.display()),
+                ::error::code::internal::MISC));
+        }
+
+        if let Some(backup) = backup {
+            let backup_path = backup.path(dest_path)?;
+            info!("Backup path: {}", backup_path.display());
+            if let Some(backup_dir) = backup_path.parent() {
+                try_!(fs::create_dir_all(backup_dir), backup_dir.to_string_lossy().into_owned(), 
+                    ::error::code::fsio::OUTPUT_BACKUP);
+            }
+            backup.rotate(dest_path)?;
+            Output::create_backup(dest_path, &backup_path)?;
         }
         else {
             debug!("No backup for in-place update of {}", dest_path.display());
         }
 
+        Output::copy_metadata(dest_path, tmp_path);
+
         debug!("Moving temp output file into place.");
-        from_result_!(::atomicwrites::replace_atomic(tmp_path, dest_path), 
+        match ::atomicwrites::replace_atomic(tmp_path, dest_path) {
+            Ok(()) => Ok(()),
+            // A temp file in a --tmp-dir cannot always be moved: the directories might be on 
+            // different file systems, or only the file itself might be writable
+            Err(ref e) if tmp_path.parent() != dest_path.parent() 
+                    && (is_cross_device(e) || e.kind() == io::ErrorKind::PermissionDenied) => {
+                warn!(concat!("Cannot move {} to {} ({}). Copying it over the original instead, ",
+                    "which is not atomic: an interruption leaves a partially written file."), 
+                    tmp_path.display(), dest_path.display(), e);
+                try_!(fs::copy(tmp_path, dest_path), dest_path.to_string_lossy().into_owned(),
+                    ::error::code::fsio::OUTPUT);
+                // The temp file has the metadata of the original, the copy does not
+                Output::copy_metadata(tmp_path, dest_path);
+                if let Err(e) = fs::remove_file(tmp_path) {
+                    warn!("Failed to remove temp output file {}: {}", tmp_path.display(), e);
+                }
+                Ok(())
+            },
+            Err(e) => Err(from_!(e, dest_path.to_string_lossy().into_owned(), 
+                ::error::code::fsio::OUTPUT))
+        }
+    }
+
+    /// Applies the permissions and modification time of the original to the temp file, so that 
+    /// they survive the swap. This is best effort: the conversion result matters more than the 
+    /// metadata, so failures are only logged.
+    fn copy_metadata(dest_path: &Path, tmp_path: &Path) {
+        let dest_stat = match fs::metadata(dest_path) {
+            Ok(stat) => stat,
+            Err(e) => {
+                debug!("Cannot read metadata of {}, not preserving it: {}", dest_path.display(), e);
+                return;
+            }
+        };
+
+        if let Err(e) = fs::set_permissions(tmp_path, dest_stat.permissions()) {
+            debug!("Failed to preserve permissions of {}: {}", dest_path.display(), e);
+        }
+
+        let atime = ::filetime::FileTime::from_last_access_time(&dest_stat);
+        let mtime = ::filetime::FileTime::from_last_modification_time(&dest_stat);
+        if let Err(e) = ::filetime::set_file_times(tmp_path, atime, mtime) {
+            debug!("Failed to preserve modification time of {}: {}", dest_path.display(), e);
+        }
+    }
+
+    /// Undoes an in-place conversion by moving the backup back onto `dest_path`. This is an 
+    /// atomic replacement, just like the final step of the conversion itself.
+    pub fn restore_backup(dest_path: &Path, backup: &Backup) -> UniResult<()> {
+        // The conversion replaced the file a symbolic link points to, so that is where the 
+        // backup is
+        let dest_path = &Output::in_place_destination(dest_path.to_path_buf(), true)?;
+        let backup_path = backup.path(dest_path)?;
+        match fs::metadata(&backup_path) {
+            Ok(ref stat) if stat.is_file() => (),
+            Ok(_) => return Err(error::usage(format!("Backup path is not a file: {}", 
+                backup_path.display())).with_minor(error::code::usage::NO_BACKUP_FILE)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => 
+                return Err(error::usage(format!("No backup to restore {} from: {} does not exist.", 
+                    dest_path.display(), backup_path.display()))
+                    .with_minor(error::code::usage::NO_BACKUP_FILE)),
+            Err(e) => return Err(from_!(e, backup_path.to_string_lossy().into_owned(), 
+                ::error::code::fsio::OUTPUT_BACKUP))
+        }
+
+        info!("Restoring {} from {}", dest_path.display(), backup_path.display());
+        from_result_!(::atomicwrites::replace_atomic(&backup_path, dest_path), 
             dest_path.to_string_lossy().into_owned(), 
             ::error::code::fsio::OUTPUT)
     }
 
+    fn create_backup(dest_path: &Path, backup_path: &Path) -> UniResult<()> {
+        // Copy the original next to the backup first, then perform the backup via an atomic 
+        // replacement operation. Existing file silently overwritten
+        let mut backup_tmp_name = ::std::ffi::OsString::from(".~");
+        backup_tmp_name.push(try_!(backup_path.file_name()
+            .ok_or("Backup path does not have file name component."), 
+            ::error::code::internal::MISC));
+        backup_tmp_name.push(".tmp");
+        let backup_tmp_path = backup_path.with_file_name(backup_tmp_name);
+
+        debug!("Creating backup of {} as {} (overwriting any existing files)", 
+            dest_path.display(), backup_path.display());
+        let copied = fs::copy(dest_path, &backup_tmp_path)
+            .and_then(|_| ::atomicwrites::replace_atomic(&backup_tmp_path, backup_path));
+        if let Err(e) = copied {
+            // Don't leave a partial copy of the original behind
+            let _ = fs::remove_file(&backup_tmp_path);
+            return Err(from_!(e, dest_path.to_string_lossy().into_owned(), 
+                ::error::code::fsio::OUTPUT_BACKUP));
+        }
+        Ok(())
+    }
+
     fn check_output_path(raw_path: &str, args: &Args) -> UniResult<Output> {
-        let some_path = PathBuf::from(raw_path);
+        let some_path = expand_path(raw_path)?;
 
         // Check if type of destination and whether it is valid
         let (dir_path, opt_file_path) = match fs::metadata(&some_path) {
@@ -201,21 +954,111 @@ impl Output {
             }
         };
 
-        // If only a directory is given, derive the file name from the input, if possible
+        // If only a directory is given, derive the file name from the input (or the name given 
+        // to standard input), if possible
         let file_path = if let Some(f) = opt_file_path { 
+            if args.flag_output_template.is_some() {
+                return Err(error::usage(
+                    "--output-template only applies if the output is a directory.".to_owned())
+                    .with_minor(error::code::usage::CONFLICTING_OPTIONS));
+            }
             f
         } else  {
-            let opt_derived_file_path = args.arg_input.as_ref().and_then(|raw_input| 
-                PathBuf::from(raw_input).file_name().map(|file_name| 
-                    dir_path.with_file_name(file_name)));
-
-            let r = opt_derived_file_path.ok_or_else(|| error::usage(
+            let input_path = args.arg_input.as_ref().or(args.flag_stdin_name.as_ref())
+                .map(PathBuf::from)
+                .filter(|input_path| input_path.file_name().is_some());
+            let input_path = input_path.ok_or_else(|| error::usage(
                 "Input file name needs to be known when no output file name is given.".to_string())
-                .with_minor(error::code::usage::MISSING_OUTPUT_FILE_NAME));
-            r?
+                .with_minor(error::code::usage::MISSING_OUTPUT_FILE_NAME))?;
+            dir_path.join(output_file_name(args, &input_path, 1)?)
+        };
+
+        if let Some(ref raw_input_path) = args.arg_input {
+            Output::check_not_input(&file_path, &expand_path(raw_input_path)?)?;
+        }
+        Output::check_overwrite(&file_path, args)?;
+        Ok(Output::OtherFile(file_path, args.flag_force))
+    }
+
+    /// Refuses to write to the input file itself, even with --force: the output is created (and 
+    /// truncated) before the input is read, so nothing would be left to convert. 
+    fn check_not_input(output_path: &Path, input_path: &Path) -> UniResult<()> {
+        // Different ways of naming the same file (e.g., via a symbolic link) resolve alike. An 
+        // output that doesn't exist yet cannot be the input.
+        let same_file = match (fs::canonicalize(output_path), fs::canonicalize(input_path)) {
+            (Ok(output_path), Ok(input_path)) => output_path == input_path,
+            _ => false
+        };
+        if same_file {
+            return Err(error::usage(format!(
+                "The output {} is the input file itself. Use --in-place to convert it in place.",
+                output_path.display())).with_minor(error::code::usage::CONFLICTING_OPTIONS));
+        }
+        Ok(())
+    }
+
+    /// Refuses to overwrite an existing output file unless --force is given. Unlike an in-place 
+    /// conversion, there is no backup of what is overwritten.
+    ///
+    /// This is checked up front so that no input is converted if one of the outputs exists. The 
+    /// output is created with the same condition (see `open_path`).
+    fn check_overwrite(file_path: &Path, args: &Args) -> UniResult<()> {
+        if !args.flag_force && file_path.exists() {
+            return Err(Output::exists_error(file_path));
+        }
+        Ok(())
+    }
+
+    fn exists_error(file_path: &Path) -> error::UniError {
+        error::usage(format!("The output file {} exists already. Pass --force to overwrite it.", 
+            file_path.display())).with_minor(error::code::usage::OUTPUT_EXISTS)
+    }
+
+    /// In-place conversion of `file_path` via a temporary file next to it (or in --tmp-dir).
+    fn in_place(file_path: PathBuf, args: &Args) -> UniResult<Output> {
+        let file_path = Output::in_place_destination(file_path, !args.flag_no_follow_symlinks)?;
+        let tmp_path = {
+            let file_name = file_path.file_name()
+                .expect("Input file path should have file name.");
+            match args.flag_tmp_dir {
+                Some(ref raw_dir) => {
+                    // Files with the same name from different directories (or another to-uni 
+                    // process) must not share a temp file
+                    let mut tmp_name = ::std::ffi::OsString::from(format!("to-uni-{}-{}-", 
+                        process::id(), TEMP_FILE_COUNT.fetch_add(1, Ordering::Relaxed)));
+                    tmp_name.push(file_name);
+                    tmp_name.push(".tmp");
+                    expand_path(raw_dir)?.join(tmp_name)
+                },
+                None => {
+                    let mut tmp_name = ::std::ffi::OsString::from(".~");
+                    tmp_name.push(file_name);
+                    tmp_name.push(".tmp");
+                    file_path.with_file_name(tmp_name)
+                }
+            }
         };
+        Ok(Output::InPlace(file_path, tmp_path, Backup::from_args(args)?))
+    }
 
-        Ok(Output::OtherFile(file_path))
+    /// The file that an in-place conversion of `file_path` replaces. For a symbolic link, that is 
+    /// the file it points to, so that the link survives the swap (and the temp file and backup 
+    /// end up next to the actual file). With `follow_symlinks` unset, links are refused instead.
+    fn in_place_destination(file_path: PathBuf, follow_symlinks: bool) -> UniResult<PathBuf> {
+        let is_symlink = fs::symlink_metadata(&file_path)
+            .map_or(false, |stat| stat.file_type().is_symlink());
+        if !is_symlink {
+            return Ok(file_path);
+        }
+        if !follow_symlinks {
+            return Err(error::usage(format!(concat!("{} is a symbolic link, not converting it ",
+                "in place (--no-follow-symlinks)."), file_path.display()))
+                .with_minor(error::code::usage::INPUT_NOT_A_FILE));
+        }
+        let target_path = try_!(fs::canonicalize(&file_path), 
+            file_path.to_string_lossy().into_owned(), ::error::code::fsio::INPUT);
+        debug!("{} is a symbolic link to {}", file_path.display(), target_path.display());
+        Ok(target_path)
     }
 
     pub fn from_args(args: &Args) -> UniResult<Output> {
@@ -224,163 +1067,1720 @@ impl Output {
         } else if let Some(ref raw_path) = args.arg_output {
             Output::check_output_path(raw_path, args)
         } else if let Some(ref raw_input_path) = args.arg_input {
-            let file_path : PathBuf = PathBuf::from(raw_input_path);
+            let file_path = expand_path(raw_input_path)?;
             Input::verify_input_path(&file_path)?;
-            let mut tmp_name = ::std::ffi::OsString::from(".~");
-            {
-                let file_name = file_path.file_name()
-                    .expect("Input file path should have file name.");
-                tmp_name.push(file_name);
-            }
-            tmp_name.push(".tmp");
-            let tmp_path = file_path.with_file_name(tmp_name);
-            Ok(Output::InPlace(file_path, tmp_path, !args.flag_no_backup))
+            Output::in_place(file_path, args)
         } else {
             Err(error::usage(
                 "Input file needs to be specified at the very least (for an in-place conversion)."
                 .to_owned())
-                .with_minor(error::code::usage::MISSING_OUTPUT))
+                .with_minor(error::code::usage::MISSING_OUTPUT_FILE_NAME))
         }
     }
 }
 
-pub struct Configuration {
+/// An input together with the destination of its converted text.
+#[derive(Debug)]
+pub struct Target {
     pub input: Input,
     pub output: Output,
-    pub patterns: HashMap<String, String>,
-    #[allow(dead_code)]
-    raw_args: Args,
-    #[allow(dead_code)]
-    raw_config: Yaml
-} 
+    /// The input is gzip-compressed and so is the output
+    pub gzip: bool
+}
 
-impl Configuration {
-    fn open_config_file(input: &Input, args: &Args) -> UniResult<(File, PathBuf)> {
-        let mut dir_path : PathBuf = input.directory()?;
-        let config_file_name = ::std::ffi::OsString::from(&args.flag_config_name);
-        loop {
-            let mut config_file_candidate = dir_path.clone();
-            config_file_candidate.push(&config_file_name);
-            match fs::File::open(&config_file_candidate) {
-                Ok(f) => {
-                    info!("Found configuration file {:?} as {}", config_file_name, 
-                        config_file_candidate.display());
-                    return Ok((f, config_file_candidate));
-                },
-                Err(e)  => {
-                    if e.kind() == io::ErrorKind::NotFound {
-                        debug!("Configuration file {:?} not found at {}", config_file_name, 
-                            config_file_candidate.display());
-                        // continue search
-                    } else {
-                        return Err(from_!(e, config_file_candidate.to_string_lossy().to_string(), 
-                            error::code::fsio::CONFIG));    
-                    }
-                }                
+impl Target {
+    /// All targets named on the command line. Either a single input/output pair or, with 
+    /// `--in-place`, one in-place target per file.
+    /// There are none for list-patterns and validate.
+    fn from_args(args: &Args) -> UniResult<Vec<Target>> {
+        if args.cmd_list_patterns || args.cmd_validate {
+            return Ok(Vec::new());
+        }
+        // Outputs are never opened in read-only modes; in particular, no temp files are created
+        let read_only = args.flag_check || args.flag_diff || args.flag_dry_run;
+        if let Some(ref raw_output_dir) = args.flag_output_dir {
+            return Target::with_output_dir(args, raw_output_dir, read_only);
+        }
+        if args.flag_in_place || args.flag_files_from.is_some() {
+            if args.flag_stdout {
+                return Err(error::usage("The options --in-place and --stdout are mutually exclusive."
+                    .to_owned()).with_minor(error::code::usage::CONFLICTING_OPTIONS));
             }
-
-            // Try parent directory. Yes we need the temporary variable, otherwise the Rust 
-            // compiler cannot prove that dir_path can be safely overwritten.
-            let old_dir_path = dir_path;
-            if let Some(parent_path) = old_dir_path.parent() {
-                dir_path = parent_path.to_path_buf();
+            if args.flag_output_template.is_some() {
+                return Err(error::usage(
+                    "--output-template needs an output directory, e.g., --output-dir.".to_owned())
+                    .with_minor(error::code::usage::CONFLICTING_OPTIONS));
             }
-            else {
-                return Err(error::usage(format!(
-                        "No configuration file {} found searching from {} upwards.", 
-                        config_file_name.to_string_lossy(), 
-                        input.directory().unwrap_or_else(|_| 
-                            PathBuf::from("unknown-file")).display()))
-                    .with_minor(error::code::usage::NO_CONFIG_FILE));
+            let mut file_paths = args.arg_file.iter().map(|raw_path| expand_path(raw_path))
+                .collect::<UniResult<Vec<_>>>()?;
+            if let Some(ref raw_list) = args.flag_files_from {
+                file_paths.extend(read_file_list(raw_list, args.flag_null)?);
+                if file_paths.is_empty() {
+                    warn!("The --files-from list is empty, there is nothing to convert.");
+                }
             }
+            file_paths.into_iter().map(|file_path| {
+                Input::verify_input_path(&file_path)?;
+                let output = if read_only {
+                    Output::Stdout
+                } else {
+                    Output::in_place(file_path.clone(), args)?
+                };
+                let gzip = args.flag_gzip || is_gzip_path(&file_path);
+                Ok(Target { input: Input::File(file_path), output, gzip })
+            }).collect()
+        } else {
+            let input = Input::from_args(args)?;
+            let output = if read_only { Output::Stdout } else { Output::from_args(args)? };
+            let gzip = args.flag_gzip || match input {
+                Input::File(ref path) => is_gzip_path(path),
+                Input::Stdin(_) => false
+            };
+            Ok(vec![Target { input, output, gzip }])
         }
     }
 
-    fn read_config_file(config_file_fd: &mut File, config_file_path: &Path) -> UniResult<Yaml> {
-        // Need to read the entire YAML file into memeory because the char-streaming-ability of 
-        // the std::io::Reader is not stable yet.
-        info!("Reading configuration file from {}", config_file_path.display());
-        let mut raw_config_text = String::new();
-        try_!(config_file_fd.read_to_string(&mut raw_config_text), 
-            config_file_path.to_string_lossy().to_string(), error::code::fsio::CONFIG);
-        
-        let mut docs = try_!(::yaml::YamlLoader::load_from_str(&raw_config_text),
-            config_file_path.to_string_lossy().to_string());
+    /// One target per input file, writing to a file of the same name in the output directory.
+    fn with_output_dir(args: &Args, raw_output_dir: &str, read_only: bool) 
+            -> UniResult<Vec<Target>> {
+        if args.flag_stdout || args.flag_in_place {
+            return Err(error::usage(
+                "--output-dir cannot be combined with --stdout or --in-place.".to_owned())
+                .with_minor(error::code::usage::CONFLICTING_OPTIONS));
+        }
+        let output_dir = expand_path(raw_output_dir)?;
+        // A single file might have been taken for the <input> of the plain conversion
+        let mut file_paths = args.arg_input.iter().chain(args.arg_file.iter())
+            .map(|raw_path| expand_path(raw_path))
+            .collect::<UniResult<Vec<_>>>()?;
+        if let Some(ref raw_list) = args.flag_files_from {
+            file_paths.extend(read_file_list(raw_list, args.flag_null)?);
+        } else if file_paths.is_empty() {
+            return Err(error::usage("--output-dir needs input files.".to_owned())
+                .with_minor(error::code::usage::MISSING_OUTPUT_FILE_NAME));
+        }
+        let mut output_paths = HashSet::new();
+        file_paths.into_iter().enumerate().map(|(index, file_path)| {
+            Input::verify_input_path(&file_path)?;
+            let output_path = output_dir.join(output_file_name(args, &file_path, index + 1)?);
+            if !output_paths.insert(output_path.clone()) {
+                return Err(error::usage(format!("Several input files would be written to {}.", 
+                    output_path.display())).with_minor(error::code::usage::CONFLICTING_OPTIONS));
+            }
+            let output = if read_only {
+                Output::Stdout
+            } else {
+                Output::check_not_input(&output_path, &file_path)?;
+                Output::check_overwrite(&output_path, args)?;
+                Output::OtherFile(output_path, args.flag_force)
+            };
+            let gzip = args.flag_gzip || is_gzip_path(&file_path);
+            Ok(Target { input: Input::File(file_path), output, gzip })
+        }).collect()
+    }
+}
 
-        if docs.len() == 0 {
-            return Err(error::usage(format!("Expected at least one document in config file {}",
-                config_file_path.display())).with_minor(error::code::usage::INVALID_CONFIG_FILE));
+/// Name of the converted copy of `input_path`, the `n`th input (counting from 1), in an output 
+/// directory: the name of the input or, with --output-template, the expanded template.
+fn output_file_name(args: &Args, input_path: &Path, n: usize) -> UniResult<PathBuf> {
+    let template = match args.flag_output_template {
+        Some(ref template) => template,
+        None => return Ok(PathBuf::from(input_path.file_name()
+            .expect("Input file path should have file name.")))
+    };
+    let part = |part: Option<&::std::ffi::OsStr>| 
+        part.map_or(String::new(), |part| part.to_string_lossy().into_owned());
+    let mut name = String::new();
+    let mut rest = template.as_str();
+    while let Some(start) = rest.find('{') {
+        name.push_str(&rest[.. start]);
+        let end = match rest[start ..].find('}') {
+            Some(length) => start + length,
+            None => return Err(error::usage(format!("Unclosed {{ in --output-template {:?}.", 
+                template)))
+        };
+        match &rest[start + 1 .. end] {
+            "name" => name.push_str(&part(input_path.file_name())),
+            "stem" => name.push_str(&part(input_path.file_stem())),
+            "ext" => name.push_str(&part(input_path.extension())),
+            "n" => name.push_str(&n.to_string()),
+            other => return Err(error::usage(format!(concat!("Unknown placeholder {{{}}} in ",
+                "--output-template. Expected {{name}}, {{stem}}, {{ext}} or {{n}}."), other)))
         }
+        rest = &rest[end + 1 ..];
+    }
+    name.push_str(rest);
+    if name.is_empty() {
+        return Err(error::usage(format!("--output-template {:?} results in an empty name for {}.", 
+            template, input_path.display()))
+            .with_minor(error::code::usage::MISSING_OUTPUT_FILE_NAME));
+    }
+    Ok(PathBuf::from(name))
+}
+
+/// Whether `e` is the failure of a rename across file systems (`EXDEV`, `ERROR_NOT_SAME_DEVICE` 
+/// on Windows).
+fn is_cross_device(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(if cfg!(windows) { 17 } else { 18 })
+}
+
+fn is_gzip_path(path: &Path) -> bool {
+    path.extension().map_or(false, |ext| ext == "gz")
+}
 
-        Ok(docs.swap_remove(0))
+/// Reads the file names of a --files-from list (- for standard input). The names are taken as 
+/// they are, without expanding `~` or variables. Empty entries are skipped, as is the carriage 
+/// return of a Windows line ending.
+fn read_file_list(raw_list: &str, null_separated: bool) -> UniResult<Vec<PathBuf>> {
+    let mut content = Vec::new();
+    if raw_list == "-" {
+        try_!(stdin().read_to_end(&mut content), "<stdin>".to_owned(), 
+            ::error::code::fsio::INPUT);
+    } else {
+        let list_path = expand_path(raw_list)?;
+        try_!(File::open(&list_path).and_then(|mut file| file.read_to_end(&mut content)), 
+            list_path.to_string_lossy().into_owned(), ::error::code::fsio::INPUT);
     }
+    let separator = if null_separated { b'\0' } else { b'\n' };
+    content.split(|&b| b == separator)
+        .map(|entry| match entry.split_last() {
+            Some((&b'\r', name)) if !null_separated => name,
+            _ => entry
+        })
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| String::from_utf8(entry.to_vec()).map(PathBuf::from).map_err(|_| 
+            error::usage(format!("File name in the --files-from list is not valid UTF-8: {}", 
+                String::from_utf8_lossy(entry)))))
+        .collect()
+}
 
-    fn parse_pattern_entry(raw_key: &Yaml, raw_value: &Yaml, config_file_path: &Path) 
-            -> UniResult<(String,String)> {
-         let key = match *raw_key {
-            Yaml::String(ref key) => key.to_string(),
-            ref other => { 
-                return Err(error::usage(format!(concat!("Error in configuration file {} ",
-                    "Expected string key, instead got: {:?}"), config_file_path.display(), other))
-                    .with_minor(error::code::usage::INVALID_CONFIG_FILE));
-            }
-        };
+/// What to-uni has been asked to do.
+pub enum Command {
+    /// Convert files according to the configuration
+    Convert(Configuration),
+    /// Restore the given files from their backups
+    Restore(Vec<PathBuf>, Backup),
+    /// Print the patterns of the configuration, as JSON if the flag is set
+    ListPatterns(Configuration, bool),
+    /// Check the configuration without converting anything
+    Validate(Configuration),
+    /// Print the given shell completion script
+    Completions(String),
+    /// Write a starter configuration file, overwriting an existing one if the flag is set
+    Init(PathBuf, bool),
+    /// Print the exit codes, as JSON if the flag is set
+    ListCodes(bool)
+}
 
-        let value = match *raw_value {
-            Yaml::String(ref value) => value.to_string(),
-            ref other => {
-                return Err(error::usage(format!(concat!("Error in configuration file {} ",
-                    "Expected value of key {} to be a string. Instead got: {:?}"),
-                    config_file_path.display(), key, other))
-                .with_minor(error::code::usage::INVALID_CONFIG_FILE));
+impl Command {
+    pub fn from_args(args: Args) -> UniResult<Command> {
+        if args.cmd_restore {
+            let backup = Backup::from_args_always(&args)?;
+            Ok(Command::Restore(args.arg_file.iter().map(PathBuf::from).collect(), backup))
+        } else if args.cmd_completions {
+            let shell = args.arg_shell.unwrap_or_default();
+            ::completions::script(&shell).map(Command::Completions).ok_or_else(|| 
+                error::usage(format!("Unknown shell {:?}. Expected one of: {}.", shell, 
+                    ::completions::SHELLS.join(", "))))
+        } else if args.cmd_list_codes {
+            Ok(Command::ListCodes(args.flag_json))
+        } else if args.cmd_init {
+            let path = match args.arg_path {
+                Some(ref raw_path) => expand_path(raw_path)?,
+                None => env::current_dir()?
+            };
+            let path = if path.is_dir() { path.join(DEFAULT_CONFIG_NAME) } else { path };
+            Ok(Command::Init(path, args.flag_force))
+        } else if args.cmd_validate {
+            Configuration::from_args(args).map(Command::Validate)
+        } else if args.cmd_list_patterns {
+            let json = args.flag_json;
+            Configuration::from_args(args).map(|config| Command::ListPatterns(config, json))
+        } else {
+            // The bare form describes a conversion just like `convert` does
+            if !args.cmd_convert {
+                debug!("No command given, converting");
             }
-        };
+            Configuration::from_args(args).map(Command::Convert)
+        }
+    }
+}
+
+/// Writes the starter configuration to `path`. Unless `force` is set, an existing file is left 
+/// alone and reported as an error.
+pub fn write_config_template(path: &Path, force: bool) -> UniResult<()> {
+    use ::atomicwrites::{AtomicFile, AllowOverwrite, DisallowOverwrite};
 
-        Ok((key, value))
+    if !force && path.exists() {
+        return Err(error::usage(format!("{} already exists. Pass --force to overwrite it.", 
+            path.display())));
     }
+    let file = AtomicFile::new(path, if force { AllowOverwrite } else { DisallowOverwrite });
+    try_!(file.write(|f| f.write_all(CONFIG_TEMPLATE.as_bytes())).map_err(io::Error::from), 
+        path.to_string_lossy().into_owned(), error::code::fsio::OUTPUT);
+    info!("Wrote a starter configuration to {}", path.display());
+    Ok(())
+}
 
-    fn parse_config(raw_config: &Yaml, config_file_path: &Path, 
-            patterns: &mut HashMap<String, String>) -> UniResult<()> {
-        let pattern_key = Yaml::String("patterns".to_string());
-        if let Yaml::Hash(ref top_level) = *raw_config {
-            if let Yaml::Hash(ref raw_pats) = top_level[&pattern_key] {
-                for (k,v) in raw_pats {
-                    let (key,value) = Configuration::parse_pattern_entry(k, v, config_file_path)?;
-                    debug!("Adding mapping {} -> {}", key, value);
-                    patterns.insert(key,value);
-                }
-                Ok(())
-            } else {
-                Err(error::usage(format!(concat!(
-                    "Expected top-level dictionary of config file {} to contain a dictionary ",
-                    "called 'patterns'."), 
-                    config_file_path.display()))
-                .with_minor(error::code::usage::INVALID_CONFIG_FILE))
-            }
-        } else {
-            Err(
-                error::usage(format!("Expected top-level of config file {} to be a dictionary.", 
-                    config_file_path.display()))
-                .with_minor(error::code::usage::INVALID_CONFIG_FILE))
+/// Patterns loaded so far, with the configuration file each of them comes from.
+struct LoadedPatterns {
+    patterns: BTreeMap<String, String>,
+    /// Configuration file that defined each pattern. Patterns from elsewhere (e.g., built-in 
+    /// sets) are missing.
+    sources: HashMap<String, PathBuf>,
+    /// Whether conflicting definitions are an error instead of a warning
+    strict_conflicts: bool
+}
+
+impl LoadedPatterns {
+    /// Adds a pattern from `config_file_path`, overriding an existing definition of `key`.
+    fn insert(&mut self, key: String, value: String, config_file_path: &Path) -> UniResult<()> {
+        match self.patterns.get(&key) {
+            Some(previous) if *previous != value => match self.sources.get(&key) {
+                Some(previous_path) => {
+                    let message = format!(concat!("Conflicting definitions of {}: ",
+                        "{:?} in {} and {:?} in {}."), key, previous, previous_path.display(), 
+                        value, config_file_path.display());
+                    if self.strict_conflicts {
+                        return Err(error::usage(message)
+                            .with_minor(error::code::usage::INVALID_CONFIG_FILE));
+                    }
+                    warn!("{} Using {:?}.", message, value);
+                },
+                None => debug!("{} overrides mapping {} -> {} with {}", 
+                    config_file_path.display(), key, previous, value)
+            },
+            _ => ()
         }
+        self.sources.insert(key.clone(), config_file_path.to_path_buf());
+        self.patterns.insert(key, value);
+        Ok(())
     }
+}
 
-    /// Creates a Configuration from command line arguments. 
-    /// This function accesses the file system in order to validate options and to 
-    /// load configuration files.
-    /// The arguments are preserved as part of the Configuration data structure.
-    pub fn from_args(args: Args) -> UniResult<Configuration> {
-        let input = Input::from_args(&args)?;
-        let output = Output::from_args(&args)?;
-        let (mut config_file_fd, config_file_path) =
-            Configuration::open_config_file(&input, &args)?;
-        let raw_config = Configuration::read_config_file(&mut config_file_fd, &config_file_path)?;
-        let mut patterns = HashMap::new();
-        Configuration::parse_config(&raw_config, &config_file_path, &mut patterns)?;
+/// Destination of the replacement records of --print0-report.
+#[derive(Debug, Clone)]
+pub enum MatchLog {
+    File(PathBuf),
+    /// A file descriptor opened by the caller
+    Fd(i32)
+}
 
-        Ok(Configuration {
-            input, output, raw_config, patterns,
-            raw_args: args
-        })
+pub struct Configuration {
+    /// Files to convert, in the order they were given on the command line.
+    pub targets: Vec<Target>,
+    /// Patterns ordered by key, so that everything derived from them is reproducible.
+    pub patterns: BTreeMap<String, String>,
+    /// Condition for replacing an occurrence of a pattern.
+    pub match_mode: MatchMode,
+    /// Remove a single space following each replaced pattern.
+    pub consume_trailing_space: bool,
+    /// Regions of the input in which patterns are left alone.
+    pub regions: RegionSettings,
+    /// Unicode normalization form of the output, if any.
+    pub normalization: Option<Normalization>,
+    /// Character encoding of the input, if not UTF-8.
+    pub input_encoding: Option<&'static Encoding>,
+    /// Fail on input that is not valid UTF-8 (after decoding).
+    pub check_utf8: bool,
+    /// Character encoding of the output, if not UTF-8.
+    pub output_encoding: Option<&'static Encoding>,
+    /// What happens to a byte order mark at the start of the input.
+    pub bom: BomMode,
+    /// Line endings of the output.
+    pub line_endings: LineEndings,
+    /// ASCII approximations that replace the replacements, if requested.
+    pub translit: Option<Transliteration>,
+    /// How the replacements are written to the output.
+    pub output_format: OutputFormat,
+    /// Text surrounding the keys in the input. Just `\` in front unless the configuration file 
+    /// says otherwise. Never empty.
+    pub markers: Vec<Marker>,
+    /// Whether to replace unicode characters with escape sequences instead of the other way round.
+    pub reverse: bool,
+    /// Only check whether the input needs conversion, don't write any output.
+    pub check: bool,
+    /// Print a diff of the changes to standard output instead of writing the output.
+    pub diff: bool,
+    /// Perform the conversion without writing any output.
+    pub dry_run: bool,
+    /// Convert the targets again whenever their input changes.
+    pub watch: bool,
+    /// Ask for confirmation before each replacement.
+    pub interactive: bool,
+    /// Number of replacements after which the rest of the input is passed through unchanged.
+    pub max_replacements: Option<usize>,
+    /// Highlight the replacements in the output with ANSI escape codes.
+    pub color: bool,
+    /// Leave the temp files of in-place conversions behind when interrupted.
+    pub keep_temp_files: bool,
+    /// Report escape sequences that the patterns didn't cover.
+    pub report_unmatched: bool,
+    /// File to write the number of replacements per pattern to.
+    pub report: Option<PathBuf>,
+    /// Where to write a record of each replacement to (--print0-report or --report-fd), in 
+    /// addition to the counts written to `report`.
+    pub match_log: Option<MatchLog>,
+    /// Print each replacement with its position in the input.
+    pub trace: bool,
+    /// Print a summary of the conversion to standard error.
+    pub stats: bool,
+    /// Print the time spent building the automaton and matching to standard error.
+    pub measure: bool,
+    /// Report the progress of each conversion to standard error.
+    pub progress: bool,
+    /// Where to write a summary of the conversion as JSON, if anywhere.
+    pub stats_json: Option<Output>,
+    /// Memory-map input files instead of streaming them.
+    pub mmap: bool,
+    /// Size of the buffer used for streaming the input.
+    pub buffer_size: usize,
+    /// Number of targets to convert in parallel.
+    pub jobs: usize,
+    /// Whether a key that is a prefix of another key is an error.
+    pub strict: bool,
+    /// Configuration files the patterns were loaded from, the closest one first.
+    pub config_files: Vec<PathBuf>,
+    /// Directories searched for configuration files, in the order they were searched. Empty if 
+    /// the configuration file was named explicitly (or with --no-config).
+    pub config_search_path: Vec<PathBuf>,
+    /// Print a summary of the configuration before converting.
+    pub show_config: bool,
+    raw_args: Args
+} 
+
+impl Configuration {
+    /// The configuration file given explicitly, along with where it was given: an explicit 
+    /// --config takes precedence over the environment. None if the configuration file is to be 
+    /// searched for (or if there is none, with --no-config).
+    fn explicit_config(args: &Args) -> Option<(String, &'static str)> {
+        if args.flag_no_config {
+            return None;
+        }
+        match args.flag_config {
+            Some(ref raw_config_path) => Some((raw_config_path.clone(), "command line")),
+            None => env::var(CONFIG_ENV_VAR).ok()
+                .filter(|raw_config_path| !raw_config_path.is_empty())
+                .map(|raw_config_path| (raw_config_path, CONFIG_ENV_VAR))
+        }
+    }
+
+    /// Locates and opens the configuration file. If `required` is false, not finding a 
+    /// configuration file is not an error. With `--merge-configs`, all configuration files up 
+    /// the hierarchy are returned, the closest one first. A configuration "file" named `-` is read 
+    /// from standard input. The directories searched are added to `searched`.
+    fn open_config_files(input: &Input, args: &Args, required: bool, searched: &mut Vec<PathBuf>) 
+            -> UniResult<Vec<(Box<Read>, PathBuf)>> {
+        let search_origin = match Configuration::explicit_config(args) {
+            Some((ref raw_config_path, source)) if raw_config_path == "-" => {
+                info!("Reading configuration from standard input ({})", source);
+                let config = Box::new(stdin()) as Box<Read>;
+                return Ok(vec![(config, PathBuf::from(STDIN_CONFIG_NAME))]);
+            },
+            Some((ref raw_config_path, source)) => {
+                let config_path = expand_path(raw_config_path)?;
+                let config_stat = try_!(fs::metadata(&config_path), 
+                    config_path.to_string_lossy().into_owned(), error::code::fsio::CONFIG);
+                if config_stat.is_file() {
+                    info!("Using configuration file {} from {}", config_path.display(), source);
+                    let f = try_!(fs::File::open(&config_path), 
+                        config_path.to_string_lossy().into_owned(), error::code::fsio::CONFIG);
+                    return Ok(vec![(Box::new(f) as Box<Read>, config_path)]);
+                }
+                // A directory serves as the origin of the usual upwards search
+                config_path
+            },
+            None => input.directory()?
+        };
+        Configuration::search_config_files(search_origin, args, required, searched)
+    }
+
+    fn search_config_files(search_origin: PathBuf, args: &Args, required: bool, 
+            searched: &mut Vec<PathBuf>) -> UniResult<Vec<(Box<Read>, PathBuf)>> {
+        let mut dir_path : PathBuf = search_origin.clone();
+        let config_file_names = Configuration::config_file_names(args)?;
+        let mut found = Vec::new();
+        // The first candidate that exists but cannot be opened, reported if nothing else is found
+        let mut skipped = None;
+        loop {
+            searched.push(dir_path.clone());
+            // All names are tried at each level before ascending; the first match wins
+            for config_file_name in &config_file_names {
+                let mut config_file_candidate = dir_path.clone();
+                config_file_candidate.push(config_file_name);
+                match fs::File::open(&config_file_candidate) {
+                    Ok(f) => {
+                        info!("Found configuration file {:?} as {}", config_file_name, 
+                            config_file_candidate.display());
+                        found.push((Box::new(f) as Box<Read>, config_file_candidate));
+                        break;
+                    },
+                    Err(e)  => {
+                        if e.kind() == io::ErrorKind::NotFound {
+                            debug!("Configuration file {:?} not found at {}", config_file_name, 
+                                config_file_candidate.display());
+                            // continue search
+                        } else {
+                            // E.g., a permission error or a symbolic link loop. A configuration 
+                            // file further up might still do.
+                            warn!("Skipping configuration file {}: {}", 
+                                config_file_candidate.display(), e);
+                            if skipped.is_none() {
+                                skipped = Some(from_!(e, 
+                                    config_file_candidate.to_string_lossy().to_string(), 
+                                    error::code::fsio::CONFIG));
+                            }
+                        }
+                    }                
+                }
+            }
+            if !found.is_empty() && !args.flag_merge_configs {
+                return Ok(found);
+            }
+
+            // The search ends at the project root (if a marker was given)
+            let at_root_marker = match args.flag_config_root_marker {
+                Some(ref marker) => {
+                    let marker_path = dir_path.join(marker);
+                    let reached = fs::symlink_metadata(&marker_path).is_ok();
+                    if reached {
+                        debug!("Stopping configuration file search at root marker {}", 
+                            marker_path.display());
+                    }
+                    reached
+                },
+                None => false
+            };
+
+            // Try parent directory. Yes we need the temporary variable, otherwise the Rust 
+            // compiler cannot prove that dir_path can be safely overwritten.
+            let old_dir_path = dir_path;
+            match old_dir_path.parent() {
+                Some(parent_path) if !at_root_marker => dir_path = parent_path.to_path_buf(),
+                _ if !found.is_empty() => return Ok(found),
+                _ => {
+                    // Rather than claiming that there is no configuration file
+                    if let Some(e) = skipped {
+                        return Err(e);
+                    }
+                    let message = format!("No configuration file {} found searching from {} up to {}.", 
+                        args.flag_config_name, search_origin.display(), 
+                        if at_root_marker { old_dir_path.display().to_string() } 
+                        else { "the file system root".to_owned() });
+                    if required {
+                        return Err(error::usage(message)
+                            .with_minor(error::code::usage::NO_CONFIG_FILE));
+                    } else {
+                        info!("{}", message);
+                        return Ok(found);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The configuration file names to look for, in order of preference.
+    fn config_file_names(args: &Args) -> UniResult<Vec<::std::ffi::OsString>> {
+        let names : Vec<_> = args.flag_config_name.split(',')
+            .map(|name| name.trim())
+            .filter(|name| !name.is_empty())
+            .map(::std::ffi::OsString::from)
+            .collect();
+        if names.is_empty() {
+            return Err(error::usage("--config-name needs at least one file name.".to_owned()));
+        }
+        Ok(names)
+    }
+
+    fn read_config_file(config_file_fd: &mut Read, config_file_path: &Path, 
+            doc_index: Option<usize>) -> UniResult<Yaml> {
+        // Need to read the entire YAML file into memory: yaml-rust only loads documents from a 
+        // &str (its parser takes chars, but the loader that builds the document isn't exposed for 
+        // that) and std::io::Read cannot stream chars. Sizing the buffer up front at least avoids 
+        // growing it repeatedly, which can leave up to twice the file size allocated for large 
+        // generated tables.
+        info!("Reading configuration file from {}", config_file_path.display());
+        let size_hint = fs::metadata(config_file_path).map(|metadata| metadata.len() as usize)
+            .unwrap_or(0);
+        let mut raw_config_text = String::with_capacity(size_hint);
+        // read_to_string fills the buffer directly, another buffer in between would only copy
+        try_!(config_file_fd.read_to_string(&mut raw_config_text), 
+            config_file_path.to_string_lossy().to_string(), error::code::fsio::CONFIG);
+
+        let is_toml = config_file_path.extension().map_or(false, |ext| ext == "toml");
+        if is_toml {
+            return Configuration::parse_toml(&raw_config_text, config_file_path);
+        }
+        
+        let mut docs = try_!(::yaml::YamlLoader::load_from_str(&raw_config_text),
+            config_file_path.to_string_lossy().to_string());
+
+        if docs.len() == 0 {
+            return Err(error::usage(format!("Expected at least one document in config file {}",
+                config_file_path.display())).with_minor(error::code::usage::INVALID_CONFIG_FILE));
+        }
+
+        if doc_index.map_or(false, |index| index >= docs.len()) {
+            return Err(error::usage(format!(
+                    "Config file {} has only {} documents, cannot use document {} (--config-doc).",
+                    config_file_path.display(), docs.len(), doc_index.unwrap()))
+                .with_minor(error::code::usage::INVALID_CONFIG_FILE));
+        }
+        if docs.len() > 1 && doc_index.is_none() {
+            warn!(concat!("Config file {} has {} documents; using the first one. Use ",
+                "--config-doc to pick another one."), config_file_path.display(), docs.len());
+        }
+
+        match docs.swap_remove(doc_index.unwrap_or(0)) {
+            Yaml::Null | Yaml::BadValue => Err(error::usage(format!(concat!(
+                    "Config file {} is empty; expected a top-level mapping with a 'patterns' ",
+                    "key."), config_file_path.display()))
+                .with_minor(error::code::usage::INVALID_CONFIG_FILE)),
+            doc => Ok(doc)
+        }
+    }
+
+    /// Parses a TOML configuration file into the same structure a YAML configuration file would 
+    /// have. That way, the rest of the configuration handling doesn't need to care about the 
+    /// format.
+    fn parse_toml(raw_config_text: &str, config_file_path: &Path) -> UniResult<Yaml> {
+        let value : ::toml::Value = try_!(raw_config_text.parse(), 
+            config_file_path.to_string_lossy().to_string());
+        Ok(Configuration::toml_to_yaml(value))
+    }
+
+    fn toml_to_yaml(value: ::toml::Value) -> Yaml {
+        use ::toml::Value;
+        match value {
+            Value::String(s) => Yaml::String(s),
+            Value::Integer(i) => Yaml::Integer(i),
+            Value::Float(f) => Yaml::Real(f.to_string()),
+            Value::Boolean(b) => Yaml::Boolean(b),
+            Value::Datetime(d) => Yaml::String(d.to_string()),
+            Value::Array(xs) => Yaml::Array(xs.into_iter().map(Configuration::toml_to_yaml).collect()),
+            Value::Table(table) => {
+                let mut hash = ::yaml::yaml::Hash::new();
+                for (k,v) in table {
+                    hash.insert(Yaml::String(k), Configuration::toml_to_yaml(v));
+                }
+                Yaml::Hash(hash)
+            }
+        }
+    }
+
+    /// Text of a scalar key or value. Numbers and booleans are taken literally as written 
+    /// (e.g., `deg: 180`), everything else is not a valid pattern entry.
+    fn scalar_text(raw: &Yaml) -> Option<String> {
+        match *raw {
+            Yaml::String(ref text) => Some(text.to_string()),
+            Yaml::Integer(number) => Some(number.to_string()),
+            Yaml::Real(ref number) => Some(number.to_string()),
+            Yaml::Boolean(flag) => Some(flag.to_string()),
+            _ => None
+        }
+    }
+
+    /// Parses a `KEY: VALUE` entry of the patterns dictionary. The value can also be a list of 
+    /// strings `[VALUE, ALIAS...]`, in which case each alias is another key for the same value. 
+    /// Returns key, value and aliases.
+    fn parse_pattern_entry(raw_key: &Yaml, raw_value: &Yaml, config_file_path: &Path) 
+            -> UniResult<(String, String, Vec<String>)> {
+         let key = match Configuration::scalar_text(raw_key) {
+            Some(key) => key,
+            None => { 
+                return Err(error::usage(format!(concat!("Error in configuration file {} ",
+                    "Expected string key, instead got: {:?}"), config_file_path.display(), raw_key))
+                    .with_minor(error::code::usage::INVALID_CONFIG_FILE));
+            }
+        };
+        Configuration::check_pattern_key(&key, raw_value, config_file_path)?;
+
+        if let Yaml::Array(ref items) = *raw_value {
+            let mut texts = Vec::with_capacity(items.len());
+            for item in items {
+                match *item {
+                    Yaml::String(ref text) => texts.push(text.clone()),
+                    _ => return Err(error::usage(format!(concat!("Error in configuration file {} ",
+                        "Expected the list for key {} to contain only strings (the value followed ",
+                        "by aliases). Instead got: {:?}"), 
+                        config_file_path.display(), key, raw_value))
+                        .with_minor(error::code::usage::INVALID_CONFIG_FILE))
+                }
+            }
+            if texts.is_empty() {
+                return Err(error::usage(format!(concat!("Error in configuration file {} ",
+                    "The list for key {} is empty. Expected the value followed by aliases."), 
+                    config_file_path.display(), key))
+                    .with_minor(error::code::usage::INVALID_CONFIG_FILE));
+            }
+            let value = texts.remove(0);
+            for alias in &texts {
+                Configuration::check_pattern_key(alias, raw_value, config_file_path)?;
+            }
+            return Ok((key, value, texts));
+        }
+
+        let value = match Configuration::scalar_text(raw_value) {
+            Some(value) => value,
+            None => {
+                return Err(error::usage(format!(concat!("Error in configuration file {} ",
+                    "Expected value of key {} to be a string. Instead got: {:?}"),
+                    config_file_path.display(), key, raw_value))
+                .with_minor(error::code::usage::INVALID_CONFIG_FILE));
+            }
+        };
+
+        Ok((key, value, Vec::new()))
+    }
+
+    fn check_pattern_key(key: &str, raw_value: &Yaml, config_file_path: &Path) -> UniResult<()> {
+        if key.trim().is_empty() {
+            // An empty pattern would be just the `\` prefix, matching every backslash
+            return Err(error::usage(format!(concat!("Error in configuration file {} ",
+                "Keys must not be empty or consist of whitespace only. Offending entry: {:?}: {:?}"), 
+                config_file_path.display(), key, raw_value))
+                .with_minor(error::code::usage::INVALID_CONFIG_FILE));
+        }
+        Ok(())
+    }
+
+    /// Adds the entries of the `patterns` dictionary to `patterns`. Entries can be grouped into 
+    /// categories (`CATEGORY: { KEY: VALUE }`); with `categories`, only the entries of these 
+    /// categories (and the ones without a category) are added. The names of all categories in the 
+    /// file are added to `found_categories`.
+    fn parse_config(raw_config: &Yaml, config_file_path: &Path, reverse: bool,
+            categories: Option<&[String]>, found_categories: &mut HashSet<String>,
+            patterns: &mut LoadedPatterns) -> UniResult<()> {
+        let pattern_key = Yaml::String("patterns".to_string());
+        let top_level = match *raw_config {
+            Yaml::Hash(ref top_level) => top_level,
+            _ => return Err(
+                error::usage(format!("Expected top-level of config file {} to be a dictionary.", 
+                    config_file_path.display()))
+                .with_minor(error::code::usage::INVALID_CONFIG_FILE))
+        };
+        let raw_pats = match top_level.get(&pattern_key) {
+            Some(&Yaml::Hash(ref raw_pats)) => raw_pats,
+            Some(other) => return Err(error::usage(format!(concat!(
+                    "Expected 'patterns' in config file {} to be a dictionary of KEY: VALUE ", 
+                    "entries. Instead got: {:?}"), 
+                    config_file_path.display(), other))
+                .with_minor(error::code::usage::INVALID_CONFIG_FILE)),
+            None => return Err(error::usage(format!(
+                    "Config file {} does not define any patterns (top-level key 'patterns' is missing).", 
+                    config_file_path.display()))
+                .with_minor(error::code::usage::INVALID_CONFIG_FILE))
+        };
+        if raw_pats.is_empty() {
+            warn!("The patterns dictionary of config file {} is empty.", config_file_path.display());
+        }
+
+        let mut entries = Vec::with_capacity(raw_pats.len());
+        for (k,v) in raw_pats {
+            match *v {
+                Yaml::Hash(ref category_pats) => {
+                    let category = match Configuration::scalar_text(k) {
+                        Some(category) => category,
+                        None => return Err(error::usage(format!(concat!(
+                            "Error in configuration file {} ",
+                            "Expected string category name, instead got: {:?}"), 
+                            config_file_path.display(), k))
+                            .with_minor(error::code::usage::INVALID_CONFIG_FILE))
+                    };
+                    found_categories.insert(category.clone());
+                    if categories.map_or(true, |names| names.contains(&category)) {
+                        entries.extend(category_pats.iter()
+                            .map(|(k, v)| (Some(category.clone()), k, v)));
+                    } else {
+                        debug!("Skipping category {} of {}", category, config_file_path.display());
+                    }
+                },
+                _ => entries.push((None, k, v))
+            }
+        }
+
+        let mut categories_by_key : HashMap<String, String> = HashMap::new();
+        for (category, k, v) in entries {
+            let (key, value, aliases) =
+                Configuration::parse_pattern_entry(k, v, config_file_path)?;
+            if let Some(category) = category {
+                for name in ::std::iter::once(&key).chain(aliases.iter()) {
+                    if let Some(other) = categories_by_key.insert(name.clone(), category.clone()) {
+                        if other != category {
+                            warn!("Key {} is defined in both category {} and category {} of {}", 
+                                name, other, category, config_file_path.display());
+                        }
+                    }
+                }
+            }
+            if reverse {
+                // The value converts back to the key itself, never to an alias
+                if !aliases.is_empty() {
+                    debug!("Ignoring aliases {:?} of {} for the reverse conversion", aliases, key);
+                }
+            } else {
+                for alias in aliases {
+                    debug!("Adding mapping {} -> {} (alias of {})", alias, value, key);
+                    patterns.insert(alias, value.clone(), config_file_path)?;
+                }
+            }
+            if value.is_empty() {
+                debug!("Adding mapping {} -> (empty, deletes matches)", key);
+            } else {
+                debug!("Adding mapping {} -> {}", key, value);
+            }
+            patterns.insert(key, value, config_file_path)?;
+        }
+        Ok(())
+    }
+
+    /// The optional top-level `prefix` (and `suffix`), which replace `\` as the text surrounding 
+    /// each key. `prefix` can also be a list, where each entry is either a prefix or a dictionary 
+    /// with `prefix` and `suffix`. A key can then be written in any of these ways.
+    fn parse_markers(raw_config: &Yaml, config_file_path: &Path) 
+            -> UniResult<Option<Vec<Marker>>> {
+        let invalid = |what: &str, other: &Yaml| error::usage(format!(
+                "Error in configuration file {} Expected {} to be a string. Instead got: {:?}", 
+                config_file_path.display(), what, other))
+            .with_minor(error::code::usage::INVALID_CONFIG_FILE);
+        let optional_text = |raw: &Yaml, what: &str| match *raw {
+            Yaml::BadValue => Ok(None),
+            Yaml::String(ref text) => Ok(Some(text.clone())),
+            ref other => Err(invalid(what, other))
+        };
+
+        let top_level_suffix = optional_text(&raw_config["suffix"], "suffix")?;
+        match raw_config["prefix"] {
+            Yaml::BadValue => Ok(top_level_suffix.map(|suffix| 
+                vec![Marker::new(::conversion::DEFAULT_PREFIX, &suffix)])),
+            Yaml::String(ref prefix) => {
+                let suffix = top_level_suffix.as_ref().map_or("", |s| s.as_str());
+                Ok(Some(vec![Marker::new(prefix, suffix)]))
+            },
+            Yaml::Array(ref entries) if !entries.is_empty() => {
+                if top_level_suffix.is_some() {
+                    return Err(error::usage(format!(concat!("Error in configuration file {} ",
+                        "With a list of prefixes, each entry needs to specify its own suffix."), 
+                        config_file_path.display()))
+                        .with_minor(error::code::usage::INVALID_CONFIG_FILE));
+                }
+                entries.iter().map(|entry| match *entry {
+                    Yaml::String(ref prefix) => Ok(Marker::new(prefix, "")),
+                    Yaml::Hash(_) => {
+                        let prefix = optional_text(&entry["prefix"], "prefix")?;
+                        let suffix = optional_text(&entry["suffix"], "suffix")?;
+                        Ok(Marker::new(prefix.as_ref().map_or("", |p| p.as_str()), 
+                            suffix.as_ref().map_or("", |s| s.as_str())))
+                    },
+                    ref other => Err(invalid("prefix", other))
+                }).collect::<UniResult<Vec<_>>>().map(Some)
+            },
+            ref other => Err(error::usage(format!(concat!("Error in configuration file {} ",
+                "Expected prefix to be a string or a non-empty list. Instead got: {:?}"), 
+                config_file_path.display(), other))
+                .with_minor(error::code::usage::INVALID_CONFIG_FILE))
+        }
+    }
+
+    /// The optional top-level `translit` dictionary of unicode text and its ASCII approximation.
+    fn parse_translit(raw_config: &Yaml, config_file_path: &Path, 
+            table: &mut HashMap<String, String>) -> UniResult<()> {
+        let invalid = |other: &Yaml| error::usage(format!(concat!("Error in configuration file {} ",
+                "Expected translit to be a dictionary of TEXT: ASCII entries. Instead got: {:?}"), 
+                config_file_path.display(), other))
+            .with_minor(error::code::usage::INVALID_CONFIG_FILE);
+        match raw_config["translit"] {
+            Yaml::BadValue => Ok(()),
+            Yaml::Hash(ref entries) => {
+                for (k, v) in entries {
+                    match (Configuration::scalar_text(k), Configuration::scalar_text(v)) {
+                        (Some(text), Some(ascii)) => { table.insert(text, ascii); },
+                        _ => return Err(invalid(&raw_config["translit"]))
+                    }
+                }
+                Ok(())
+            },
+            ref other => Err(invalid(other))
+        }
+    }
+
+    /// The optional top-level `match_mode`.
+    fn parse_match_mode(raw_config: &Yaml, config_file_path: &Path) -> UniResult<Option<MatchMode>> {
+        match raw_config["match_mode"] {
+            Yaml::BadValue => Ok(None),
+            Yaml::String(ref name) => MatchMode::from_name(name).map(Some).ok_or_else(|| 
+                error::usage(format!(concat!("Error in configuration file {} ",
+                    "Unknown match mode {:?}. Expected raw, word-boundary or braces."), 
+                    config_file_path.display(), name))
+                .with_minor(error::code::usage::INVALID_CONFIG_FILE)),
+            ref other => Err(error::usage(format!(concat!("Error in configuration file {} ",
+                "Expected match_mode to be a string. Instead got: {:?}"), 
+                config_file_path.display(), other))
+                .with_minor(error::code::usage::INVALID_CONFIG_FILE))
+        }
+    }
+
+    /// For the reverse direction, replacements become patterns and need to identify their key 
+    /// unambiguously. Checks the final set of patterns, wherever they come from (configuration 
+    /// files, built-in sets or --pattern); `sources` names the configuration file of a key.
+    fn check_reversible(patterns: &BTreeMap<String, String>, sources: &HashMap<String, PathBuf>) 
+            -> UniResult<()> {
+        let origin = |key: &str| match sources.get(key) {
+            Some(path) => path.display().to_string(),
+            None => "the command line or a built-in set".to_owned()
+        };
+        // Patterns given on the command line are not part of any configuration file
+        let minor = |key: &str| if sources.contains_key(key) {
+            error::code::usage::INVALID_CONFIG_FILE
+        } else {
+            error::code::usage::MALFORMED_PATTERN
+        };
+        let mut keys_by_value : HashMap<&str, &str> = HashMap::new();
+        for (key, value) in patterns {
+            if value.is_empty() {
+                return Err(error::usage(format!(
+                    "The value of key {} (from {}) is empty and cannot be converted back.",
+                    key, origin(key))).with_minor(minor(key)));
+            }
+            if let Some(other_key) = keys_by_value.insert(value, key) {
+                return Err(error::usage(format!(concat!("Keys {} (from {}) and {} (from {}) ",
+                    "both map to {:?}, so the reverse conversion is ambiguous."),
+                    other_key, origin(other_key), key, origin(key), value))
+                    .with_minor(minor(key)));
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_pattern_argument(raw_mapping: &str) -> UniResult<(String, String)> {
+        match raw_mapping.find('=') {
+            Some(idx) if !raw_mapping[.. idx].trim().is_empty() => 
+                Ok((raw_mapping[.. idx].to_string(), raw_mapping[idx + 1 ..].to_string())),
+            _ => Err(error::usage(format!(
+                    "Expected pattern of the form KEY=VALUE, instead got: {}", raw_mapping))
+                .with_minor(error::code::usage::MALFORMED_PATTERN))
+        }
+    }
+
+    /// The optional top-level `directive_marker`.
+    fn parse_directive_marker(raw_config: &Yaml, config_file_path: &Path) 
+            -> UniResult<Option<String>> {
+        match raw_config["directive_marker"] {
+            Yaml::BadValue => Ok(None),
+            Yaml::String(ref marker) => Ok(Some(marker.clone())),
+            ref other => Err(error::usage(format!(concat!("Error in configuration file {} ",
+                "Expected directive_marker to be a string. Instead got: {:?}"), 
+                config_file_path.display(), other))
+                .with_minor(error::code::usage::INVALID_CONFIG_FILE))
+        }
+    }
+
+    fn parse_encoding(label: &Option<String>) -> UniResult<Option<&'static Encoding>> {
+        match *label {
+            Some(ref label) => ::encoding::from_label(label).map(Some).ok_or_else(|| 
+                error::usage(format!("Unknown character encoding: {}", label))),
+            None => Ok(None)
+        }
+    }
+
+    fn region_settings(args: &Args, directive_marker: String) -> RegionSettings {
+        let mut regions = RegionSettings::default();
+        regions.directive_marker = directive_marker;
+        regions.comments = args.flag_respect_comments;
+        regions.math_only = args.flag_math_only;
+        if args.flag_respect_verbatim {
+            regions.verbatim_environments = args.flag_verbatim_environments.split(',')
+                .map(|name| name.trim())
+                .filter(|name| !name.is_empty())
+                .map(|name| name.to_owned())
+                .collect();
+        }
+        regions
+    }
+
+    /// Prints where the configuration comes from and what it amounts to (`--show-config`) to 
+    /// standard error.
+    pub fn show(&self) -> UniResult<()> {
+        let stderr = io::stderr();
+        let mut out = stderr.lock();
+        try_!(self.write_summary(&mut out), "<stderr>".to_string(), error::code::fsio::OUTPUT);
+        Ok(())
+    }
+
+    fn write_summary(&self, out: &mut Write) -> io::Result<()> {
+        let args = &self.raw_args;
+        if args.flag_no_config {
+            writeln!(out, "Configuration files: none (--no-config)")?;
+        } else if self.config_files.is_empty() {
+            writeln!(out, "Configuration files: none found (looking for {})", 
+                args.flag_config_name)?;
+        } else {
+            writeln!(out, "Configuration files (closest first):")?;
+            for path in &self.config_files {
+                writeln!(out, "    {}", path.display())?;
+            }
+        }
+        if !self.config_search_path.is_empty() {
+            writeln!(out, "Searched directories:")?;
+            for dir_path in &self.config_search_path {
+                writeln!(out, "    {}", dir_path.display())?;
+            }
+        }
+        if let Some(ref builtin_names) = args.flag_builtin {
+            writeln!(out, "Built-in pattern sets: {}", builtin_names)?;
+        }
+        if !args.flag_pattern.is_empty() {
+            writeln!(out, "Patterns from the command line: {}", args.flag_pattern.len())?;
+        }
+        let markers : Vec<_> = self.markers.iter()
+            .map(|marker| format!("{}KEY{}", marker.prefix, marker.suffix)).collect();
+        writeln!(out, "Pattern markers: {}", markers.join(", "))?;
+        writeln!(out, "Match mode: {:?}", self.match_mode)?;
+        writeln!(out, "Patterns: {}", self.patterns.len())
+    }
+
+    /// Creates a Configuration from command line arguments. 
+    /// This function accesses the file system in order to validate options and to 
+    /// load configuration files.
+    /// The arguments are preserved as part of the Configuration data structure.
+    pub fn from_args(args: Args) -> UniResult<Configuration> {
+        if args.flag_no_config && args.flag_config.is_some() {
+            return Err(error::usage("The options --no-config and --config are mutually exclusive."
+                .to_owned()).with_minor(error::code::usage::CONFLICTING_OPTIONS));
+        }
+
+        if args.flag_check && args.flag_diff {
+            return Err(error::usage("The options --check and --diff are mutually exclusive."
+                .to_owned()).with_minor(error::code::usage::CONFLICTING_OPTIONS));
+        }
+
+        if args.flag_translit && args.flag_reverse {
+            return Err(error::usage("The options --translit and --reverse are mutually exclusive."
+                .to_owned()).with_minor(error::code::usage::CONFLICTING_OPTIONS));
+        }
+
+        if !args.flag_only.is_empty() && !args.flag_exclude.is_empty() {
+            return Err(error::usage("The options --only and --exclude are mutually exclusive."
+                .to_owned()).with_minor(error::code::usage::CONFLICTING_OPTIONS));
+        }
+
+        if args.flag_jobs == 0 {
+            return Err(error::usage("--jobs needs to be at least 1.".to_owned())
+                .with_minor(error::code::usage::INVALID_ARGUMENT));
+        }
+
+        let targets = Target::from_args(&args)?;
+        let stdin_input = targets.iter().any(|target| match target.input {
+            Input::Stdin(_) => true,
+            Input::File(_) => false
+        });
+        // A --files-from list on standard input takes it just like an input would
+        let stdin_taken = stdin_input 
+            || args.flag_files_from.as_ref().map_or(false, |list| list == "-");
+        // The configuration can also be directed to standard input via the environment
+        let stdin_config = Configuration::explicit_config(&args)
+            .map_or(false, |(raw_config_path, _)| raw_config_path == "-");
+        if stdin_taken && stdin_config {
+            return Err(error::usage(format!(concat!("The configuration and the input cannot both ",
+                "come from standard input. Name an input file when using --config - (or {}=-)."),
+                CONFIG_ENV_VAR)).with_minor(error::code::usage::CONFLICTING_OPTIONS));
+        }
+        if args.flag_interactive && (stdin_taken || stdin_config) {
+            return Err(error::usage(concat!("--interactive reads the answers from standard input, ",
+                "so neither the input nor the configuration can come from there.").to_owned())
+                .with_minor(error::code::usage::CONFLICTING_OPTIONS));
+        }
+        if args.flag_interactive && args.flag_jobs > 1 {
+            return Err(error::usage("The options --interactive and --jobs are mutually exclusive."
+                .to_owned()).with_minor(error::code::usage::CONFLICTING_OPTIONS));
+        }
+        if args.flag_watch && stdin_input {
+            return Err(error::usage("--watch needs input files, it cannot watch standard input."
+                .to_owned()).with_minor(error::code::usage::CONFLICTING_OPTIONS));
+        }
+        let mut patterns = BTreeMap::new();
+        if let Some(ref builtin_names) = args.flag_builtin {
+            for builtin_name in builtin_names.split(',').map(|n| n.trim()) {
+                let builtin_patterns = builtin::patterns(builtin_name).ok_or_else(|| 
+                    error::usage(format!("Unknown built-in pattern set: {}", builtin_name))
+                    .with_minor(error::code::usage::INVALID_ARGUMENT))?;
+                debug!("Adding {} mappings from built-in pattern set {}", builtin_patterns.len(), 
+                    builtin_name);
+                patterns.extend(builtin_patterns);
+            }
+        }
+
+        // With a built-in pattern set, the configuration file only supplements the patterns
+        let config_required = args.flag_builtin.is_none();
+        let mut config_search_path = Vec::new();
+        let config_files = if args.flag_no_config {
+            debug!("Skipping configuration file search (--no-config)");
+            Vec::new()
+        } else {
+            // The search starts at the first target. Without targets (list-patterns, validate), 
+            // it starts at the current directory, just like for standard input.
+            let stdin = Input::Stdin(None);
+            let search_input = targets.first().map_or(&stdin, |target| &target.input);
+            Configuration::open_config_files(search_input, &args, config_required, 
+                &mut config_search_path)?
+        };
+        // More specific (closer) configuration files override the ones further up
+        let mut markers = vec![Marker::default()];
+        let mut match_mode = MatchMode::default();
+        let mut directive_marker = ::regions::DEFAULT_DIRECTIVE_MARKER.to_owned();
+        let mut translit_table = HashMap::new();
+        let categories : Option<Vec<String>> = args.flag_category.as_ref().map(|names| 
+            names.split(',').map(|name| name.trim().to_owned()).collect());
+        let mut found_categories = HashSet::new();
+        let mut loaded = LoadedPatterns { 
+            patterns, 
+            sources: HashMap::new(), 
+            strict_conflicts: args.flag_strict_conflicts 
+        };
+        let config_file_paths : Vec<PathBuf> = config_files.iter()
+            .map(|&(_, ref path)| path.clone()).collect();
+        for (mut config_file_fd, config_file_path) in config_files.into_iter().rev() {
+            debug!("Merging patterns from {}", config_file_path.display());
+            let raw_config = Configuration::read_config_file(&mut *config_file_fd, 
+                &config_file_path, args.flag_config_doc)?;
+            Configuration::parse_config(&raw_config, &config_file_path, args.flag_reverse, 
+                categories.as_ref().map(|names| &names[..]), &mut found_categories, 
+                &mut loaded)?;
+            if let Some(config_markers) = Configuration::parse_markers(&raw_config, 
+                    &config_file_path)? {
+                debug!("Using pattern markers {:?} from {}", config_markers, 
+                    config_file_path.display());
+                markers = config_markers;
+            }
+            if let Some(config_match_mode) = Configuration::parse_match_mode(&raw_config, 
+                    &config_file_path)? {
+                match_mode = config_match_mode;
+            }
+            if let Some(config_directive_marker) = Configuration::parse_directive_marker(
+                    &raw_config, &config_file_path)? {
+                directive_marker = config_directive_marker;
+            }
+            Configuration::parse_translit(&raw_config, &config_file_path, &mut translit_table)?;
+        }
+
+        let (mut patterns, mut sources) = (loaded.patterns, loaded.sources);
+        if let Some(ref names) = categories {
+            if let Some(missing) = names.iter().find(|name| !found_categories.contains(*name)) {
+                return Err(error::usage(format!(
+                    "The configuration does not define a category named {}.", missing)));
+            }
+        }
+
+        if let Some(ref name) = args.flag_match_mode {
+            match_mode = MatchMode::from_name(name).ok_or_else(|| error::usage(format!(
+                "Unknown match mode {:?}. Expected raw, word-boundary or braces.", name)))?;
+        }
+
+        let normalization = match args.flag_normalize {
+            Some(ref name) => Some(Normalization::from_name(name).ok_or_else(|| error::usage(
+                format!("Unknown normalization form {:?}. Expected nfc or nfd.", name)))?),
+            None => None
+        };
+
+        let input_encoding = Configuration::parse_encoding(&args.flag_input_encoding)?;
+        let output_encoding = Configuration::parse_encoding(&args.flag_output_encoding)?;
+        if let Some(encoding) = output_encoding {
+            if encoding.output_encoding() != encoding {
+                return Err(error::usage(format!(
+                    "The output cannot be written as {}, it is only supported for the input.", 
+                    encoding.name())));
+            }
+        }
+
+        let bom = BomMode::from_name(&args.flag_bom).ok_or_else(|| error::usage(format!(
+            "Unknown --bom mode {:?}. Expected keep, strip or add.", args.flag_bom))
+            .with_minor(error::code::usage::INVALID_ARGUMENT))?;
+        if bom == BomMode::Add && output_encoding.map_or(false, |encoding| encoding != UTF_8) {
+            return Err(error::usage("--bom add requires UTF-8 output.".to_owned())
+                .with_minor(error::code::usage::CONFLICTING_OPTIONS));
+        }
+
+        let line_endings = LineEndings::from_name(&args.flag_line_endings).ok_or_else(|| 
+            error::usage(format!("Unknown line ending style {:?}. Expected preserve, lf or crlf.", 
+                args.flag_line_endings)))?;
+
+        let output_format = OutputFormat::from_name(&args.flag_output_format).ok_or_else(|| 
+            error::usage(format!(concat!("Unknown output format {:?}. Expected unicode, ncr, ",
+                "html-entity, rust-escape or json-escape."), args.flag_output_format)))?;
+        if output_format != OutputFormat::Unicode && args.flag_reverse {
+            return Err(error::usage("The options --output-format and --reverse are mutually exclusive."
+                .to_owned()).with_minor(error::code::usage::CONFLICTING_OPTIONS));
+        }
+
+        let translit = if args.flag_translit {
+            let missing = MissingTranslit::from_name(&args.flag_translit_missing).ok_or_else(|| 
+                error::usage(format!(concat!("Unknown action {:?} for --translit-missing. ",
+                    "Expected pass or error."), args.flag_translit_missing)))?;
+            Some(Transliteration { table: translit_table, missing })
+        } else {
+            None
+        };
+
+        for raw_mapping in &args.flag_pattern {
+            let (key, value) = Configuration::parse_pattern_argument(raw_mapping)?;
+            debug!("Adding mapping {} -> {} from command line", key, value);
+            sources.remove(&key);
+            patterns.insert(key, value);
+        }
+
+        if !args.flag_only.is_empty() {
+            patterns.retain(|key, _| args.flag_only.iter().any(|glob| glob_matches(glob, key)));
+            info!("{} patterns left after --only", patterns.len());
+        }
+        if !args.flag_exclude.is_empty() {
+            patterns.retain(|key, _| !args.flag_exclude.iter().any(|glob| glob_matches(glob, key)));
+            info!("{} patterns left after --exclude", patterns.len());
+        }
+        if args.flag_reverse {
+            Configuration::check_reversible(&patterns, &sources)?;
+        }
+
+        let stats_json = match args.flag_stats_file {
+            Some(ref raw_stats_path) => Some(Output::OtherFile(expand_path(raw_stats_path)?, true)),
+            None if args.flag_stats_json => Some(Output::Stdout),
+            None => None
+        };
+        let read_only = args.flag_check || args.flag_diff || args.flag_dry_run;
+        let text_to_stdout = args.flag_diff || (!read_only && targets.iter().any(|target| 
+            match target.output {
+                Output::Stdout => true,
+                _ => false
+            }));
+        if text_to_stdout && stats_json.as_ref().map_or(false, |output| match *output {
+                Output::Stdout => true,
+                _ => false
+            }) {
+            return Err(error::usage(concat!("The summary of --stats-json and the converted text ",
+                "cannot both go to standard output. Use --stats-file.").to_owned())
+                .with_minor(error::code::usage::CONFLICTING_OPTIONS));
+        }
+
+        let color_wanted = match args.flag_color.as_str() {
+            "auto" => stdout().is_terminal(),
+            "always" => true,
+            "never" => false,
+            other => return Err(error::usage(format!(
+                "Unknown --color mode {:?}. Expected auto, always or never.", other)))
+        };
+        // Written files must never contain escape codes, whatever --color says
+        let color = color_wanted && !read_only && !targets.is_empty() 
+            && targets.iter().all(|target| match target.output {
+                Output::Stdout => true,
+                _ => false
+            });
+
+        let report = match args.flag_report {
+            Some(ref raw_report_path) => Some(expand_path(raw_report_path)?),
+            None => None
+        };
+        let match_log = match (args.flag_print0_report.as_ref(), args.flag_report_fd) {
+            (None, None) => None,
+            (Some(raw_log_path), None) => Some(MatchLog::File(expand_path(raw_log_path)?)),
+            (None, Some(fd)) => Some(MatchLog::Fd(fd)),
+            (Some(_), Some(_)) => return Err(error::usage(
+                "The options --print0-report and --report-fd are mutually exclusive.".to_owned())
+                .with_minor(error::code::usage::CONFLICTING_OPTIONS))
+        };
+        // The records would end up in the middle of the converted text (or the diff)
+        if args.flag_report_fd == Some(1) && text_to_stdout {
+            return Err(error::usage(concat!("--report-fd 1 cannot be used with output to ",
+                "standard output. Pass another file descriptor or --print0-report=FILE.").to_owned())
+                .with_minor(error::code::usage::CONFLICTING_OPTIONS));
+        }
+
+        Ok(Configuration {
+            targets, patterns, markers, match_mode,
+            consume_trailing_space: args.flag_consume_trailing_space,
+            regions: Configuration::region_settings(&args, directive_marker),
+            normalization, translit, output_format, input_encoding, output_encoding,
+            bom, line_endings,
+            check_utf8: args.flag_check_utf8,
+            reverse: args.flag_reverse,
+            check: args.flag_check,
+            diff: args.flag_diff,
+            dry_run: args.flag_dry_run,
+            watch: args.flag_watch,
+            interactive: args.flag_interactive,
+            max_replacements: args.flag_max_replacements,
+            color,
+            keep_temp_files: args.flag_keep_temp_files,
+            report_unmatched: args.flag_report_unmatched,
+            report,
+            match_log,
+            trace: args.flag_trace,
+            stats: args.flag_stats,
+            measure: args.flag_measure,
+            // Reports of several files at once would overwrite each other
+            progress: args.flag_jobs == 1 && (args.flag_force_progress 
+                || (args.flag_progress && io::stderr().is_terminal())),
+            stats_json,
+            mmap: args.flag_mmap,
+            buffer_size: args.flag_buffer_size,
+            jobs: args.flag_jobs,
+            strict: args.flag_strict,
+            config_files: config_file_paths,
+            config_search_path,
+            show_config: args.flag_show_config,
+            raw_args: args
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing::{self, TempDir};
+
+    /// Runs `f` with the environment variable `name` set to `value`, restoring it afterwards.
+    fn with_env_var<T, F: FnOnce() -> T>(name: &str, value: &str, f: F) -> T {
+        let _guard = testing::lock_env();
+        let previous = env::var_os(name);
+        env::set_var(name, value);
+        let result = f();
+        match previous {
+            Some(previous) => env::set_var(name, previous),
+            None => env::remove_var(name)
+        }
+        result
+    }
+
+    /// Loads the configuration file `text` for converting standard input.
+    fn with_config_file(text: &str) -> UniResult<Configuration> {
+        let dir = TempDir::new();
+        let path = dir.write("to-uni.yml", text.as_bytes());
+        Configuration::from_args(testing::args(
+            &["--config", path.to_str().unwrap(), "-", "--stdout"]))
+    }
+
+    fn usage_minor<T>(result: UniResult<T>) -> u8 {
+        match result {
+            Ok(_) => panic!("Expected a usage error"),
+            Err(err) => {
+                assert_eq!(err.code_major(), 0, "Expected a usage error, got {}", err);
+                err.code_minor()
+            }
+        }
+    }
+
+    #[test]
+    fn output_onto_the_input_is_refused() {
+        let dir = TempDir::new();
+        let input = dir.write("chapter.tex", b"\\alpha");
+        let same = dir.path().join(".").join("chapter.tex");
+        let err = Output::check_not_input(&same, &input).unwrap_err();
+        assert_eq!(err.code_major(), 0);
+        assert_eq!(err.code_minor(), error::code::usage::CONFLICTING_OPTIONS);
+        Output::check_not_input(&dir.path().join("other.tex"), &input).unwrap();
+    }
+
+    #[test]
+    fn configuration_from_the_environment_precedes_the_search_but_not_the_command_line() {
+        let dir = TempDir::new();
+        dir.write("to-uni.yml", "patterns:\n  alpha: found\n".as_bytes());
+        let from_env = dir.write("env.yml", "patterns:\n  alpha: environment\n".as_bytes());
+        let from_args = dir.write("args.yml", "patterns:\n  alpha: command line\n".as_bytes());
+        let input = dir.write("doc.tex", b"\\alpha");
+        let input = input.to_str().unwrap();
+        let alpha = |argv: &[&str]| with_env_var(CONFIG_ENV_VAR, from_env.to_str().unwrap(), ||
+            Configuration::from_args(testing::args(argv)).unwrap().patterns["alpha"].clone());
+
+        assert_eq!(alpha(&[input, "--stdout"]), "environment");
+        assert_eq!(alpha(&["--config", from_args.to_str().unwrap(), input, "--stdout"]),
+            "command line");
+        assert_eq!(with_env_var(CONFIG_ENV_VAR, "", || Configuration::from_args(
+            testing::args(&[input, "--stdout"])).unwrap().patterns["alpha"].clone()), "found");
+    }
+
+    #[test]
+    fn search_finds_toml_by_default_but_prefers_yaml() {
+        let dir = TempDir::new();
+        dir.write(".root", b"");
+        dir.write("to-uni.toml", "[patterns]\nalpha = \"toml\"\n".as_bytes());
+        let input = dir.write("doc.tex", b"\\alpha");
+        let alpha = || with_env_var(CONFIG_ENV_VAR, "", || Configuration::from_args(testing::args(
+                &["--config-root-marker=.root", input.to_str().unwrap(), "--stdout"]))
+            .unwrap().patterns["alpha"].clone());
+        assert_eq!(alpha(), "toml");
+        dir.write("to-uni.yml", "patterns:\n  alpha: yaml\n".as_bytes());
+        assert_eq!(alpha(), "yaml");
+    }
+
+    #[test]
+    fn configuration_from_stdin_via_the_environment_conflicts_with_stdin_input() {
+        let minor = with_env_var(CONFIG_ENV_VAR, "-", || 
+            usage_minor(Configuration::from_args(testing::args(&["-", "--stdout"]))));
+        assert_eq!(minor, error::code::usage::CONFLICTING_OPTIONS);
+    }
+
+    #[test]
+    fn configuration_from_stdin_via_the_environment_conflicts_with_interactive() {
+        let dir = TempDir::new();
+        let input = dir.write("chapter.tex", b"\\alpha");
+        let input = input.to_str().unwrap();
+        let minor = with_env_var(CONFIG_ENV_VAR, "-", || usage_minor(Configuration::from_args(
+            testing::args(&["--interactive", input, "--stdout"]))));
+        assert_eq!(minor, error::code::usage::CONFLICTING_OPTIONS);
+    }
+
+    #[test]
+    fn match_log_on_stdout_conflicts_with_output_to_stdout() {
+        let minor = usage_minor(Configuration::from_args(testing::args(
+            &["--no-config", "--builtin=greek", "--report-fd=1", "-", "--stdout"])));
+        assert_eq!(minor, error::code::usage::CONFLICTING_OPTIONS);
+        let minor = usage_minor(Configuration::from_args(testing::args(
+            &["--no-config", "--builtin=greek", "--report-fd=3", "--print0-report=records", "-"])));
+        assert_eq!(minor, error::code::usage::CONFLICTING_OPTIONS);
+    }
+
+    #[test]
+    fn reverse_checks_patterns_from_the_command_line_against_built_in_sets() {
+        let minor = usage_minor(Configuration::from_args(testing::args(&["--no-config", 
+            "--builtin=greek", "--pattern=a=α", "--reverse", "-", "--stdout"])));
+        assert_eq!(minor, error::code::usage::MALFORMED_PATTERN);
+        Configuration::from_args(testing::args(&["--no-config", "--builtin=greek", 
+            "--pattern=a=α", "--exclude=alpha", "--reverse", "-", "--stdout"])).unwrap();
+    }
+
+    #[test]
+    fn reverse_checks_merged_configuration_files() {
+        let _guard = testing::lock_env();
+        let dir = TempDir::new();
+        dir.write(".root", b"");
+        dir.write("to-uni.yml", "patterns:\n  alpha: α\n".as_bytes());
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        dir.write("sub/to-uni.yml", "patterns:\n  a: α\n".as_bytes());
+        let input = dir.write("sub/doc.tex", "α".as_bytes());
+        let minor = usage_minor(Configuration::from_args(testing::args(&["--merge-configs", 
+            "--config-root-marker=.root", "--reverse", input.to_str().unwrap(), "--stdout"])));
+        assert_eq!(minor, error::code::usage::INVALID_CONFIG_FILE);
+    }
+
+    #[test]
+    fn empty_value_deletes_matches() {
+        let config = with_config_file("patterns:\n  todo: \"\"\n  alpha: α\n").unwrap();
+        assert_eq!(config.patterns["todo"], "");
+        let converter = ::conversion::Converter::new(&config.patterns);
+        let mut output = Vec::new();
+        converter.convert(&b"\\alpha\\todo is \\todo{}done"[..], &mut output).unwrap();
+        assert_eq!(output, "α is {}done".as_bytes());
+    }
+
+    #[test]
+    fn empty_keys_are_rejected() {
+        for text in &["patterns:\n  \"\": x\n", "patterns:\n  \"  \": x\n",
+                "patterns:\n  alpha: [α, \" \"]\n"] {
+            let err = with_config_file(text).err().expect("An empty key should be rejected");
+            assert_eq!((err.code_major(), err.code_minor()), 
+                (0, error::code::usage::INVALID_CONFIG_FILE), "{}", text);
+            assert!(err.to_string().contains("Offending entry"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn numbers_and_booleans_are_taken_literally() {
+        let config = with_config_file(
+            "patterns:\n  deg: 180\n  half: 0.5\n  approx: true\n  42: answer\n").unwrap();
+        let entries : Vec<(&str, &str)> = config.patterns.iter()
+            .map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        assert_eq!(entries, vec![("42", "answer"), ("approx", "true"), ("deg", "180"), 
+            ("half", "0.5")]);
+        for text in &["patterns:\n  greek:\n    alpha: {a: b}\n", "patterns:\n  alpha: [[α]]\n", 
+                "patterns:\n  alpha: ~\n"] {
+            assert_eq!(usage_minor(with_config_file(text)), 
+                error::code::usage::INVALID_CONFIG_FILE, "{}", text);
+        }
+    }
+
+    #[test]
+    fn key_is_reachable_through_each_prefix() {
+        let config = with_config_file(concat!("prefix:\n  - \"\\\\\"\n", 
+            "  - {prefix: \":\", suffix: \":\"}\npatterns:\n  alpha: α\n")).unwrap();
+        assert_eq!(config.markers, vec![Marker::new("\\", ""), Marker::new(":", ":")]);
+        let converter = ::conversion::Converter::with_markers(&config.patterns, &config.markers);
+        let mut output = Vec::new();
+        converter.convert(&b"\\alpha, :alpha: and :alpha"[..], &mut output).unwrap();
+        assert_eq!(output, "α, α and :alpha".as_bytes());
+    }
+
+    #[test]
+    fn list_value_is_the_replacement_followed_by_aliases() {
+        let config = with_config_file("patterns:\n  rightarrow: [→, to, ra]\n").unwrap();
+        let entries : Vec<(&str, &str)> = config.patterns.iter()
+            .map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        assert_eq!(entries, vec![("ra", "→"), ("rightarrow", "→"), ("to", "→")]);
+        for text in &["patterns:\n  rightarrow: [→, 1]\n", "patterns:\n  rightarrow: []\n"] {
+            assert_eq!(usage_minor(with_config_file(text)), 
+                error::code::usage::INVALID_CONFIG_FILE, "{}", text);
+        }
+    }
+
+    #[test]
+    fn empty_config_document_is_reported() {
+        for text in &["~\n", "--- ~\n"] {
+            let err = with_config_file(text).err().expect("An empty document should be rejected");
+            assert_eq!((err.code_major(), err.code_minor()), 
+                (0, error::code::usage::INVALID_CONFIG_FILE), "{:?}", text);
+            assert!(err.to_string().contains("is empty"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn config_failures_have_stable_exit_codes() {
+        let error_code = |result: UniResult<Configuration>| 
+            result.err().expect("The configuration should be rejected").error_code();
+        assert_eq!(error_code(with_config_file("")), 8);
+        assert_eq!(error_code(with_config_file("~\n")), 8);
+        assert_eq!(error_code(with_config_file("patterns: [\n")), 30);
+        assert_eq!(error_code(with_config_file("prefix: \"\\\\\"\n")), 8);
+        assert_eq!(error_code(with_config_file("patterns:\n  alpha: ~\n")), 8);
+
+        let _guard = testing::lock_env();
+        let dir = TempDir::new();
+        dir.write(".root", b"");
+        let input = dir.write("doc.tex", b"\\alpha");
+        assert_eq!(error_code(Configuration::from_args(testing::args(
+            &["--config-root-marker=.root", input.to_str().unwrap(), "--stdout"]))), 7);
+    }
+
+    /// A symbolic link loop stands in for an unreadable file: permissions don't stop root.
+    #[cfg(unix)]
+    #[test]
+    fn search_skips_an_unreadable_config_file() {
+        let _guard = testing::lock_env();
+        let dir = TempDir::new();
+        dir.write(".root", b"");
+        dir.write("to-uni.yml", "patterns:\n  alpha: α\n".as_bytes());
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        ::std::os::unix::fs::symlink("to-uni.yml", dir.path().join("sub/to-uni.yml")).unwrap();
+        let input = dir.write("sub/doc.tex", b"\\alpha");
+        let input = input.to_str().unwrap();
+        let config = Configuration::from_args(testing::args(
+            &["--config-root-marker=.root", input, "--stdout"])).unwrap();
+        assert_eq!(config.patterns["alpha"], "α");
+
+        // Without a configuration file further up, the unreadable one is reported
+        fs::remove_file(dir.path().join("to-uni.yml")).unwrap();
+        let err = Configuration::from_args(testing::args(
+            &["--config-root-marker=.root", input, "--stdout"])).err().unwrap();
+        assert_eq!((err.code_major(), err.code_minor()), (2, error::code::fsio::CONFIG));
+    }
+
+    #[test]
+    fn args_are_deserialized_from_the_usage() {
+        let args = testing::args(&["convert", "-vv", "--in-place", "--jobs=4", "--builtin=greek",
+            "--pattern=a=α", "--pattern", "b=β", "--exclude=alpha", "-B", "--backup-keep=3",
+            "a.tex", "b.tex"]);
+        assert!(args.cmd_convert && args.flag_in_place && args.flag_no_backup);
+        assert_eq!(args.flag_verbose, 2);
+        assert_eq!(args.flag_jobs, 4);
+        assert_eq!(args.flag_builtin, Some("greek".to_owned()));
+        assert_eq!(args.flag_pattern, vec!["a=α".to_owned(), "b=β".to_owned()]);
+        assert_eq!(args.flag_exclude, vec!["alpha".to_owned()]);
+        assert_eq!(args.flag_backup_keep, 3);
+        assert_eq!(args.arg_file, vec!["a.tex".to_owned(), "b.tex".to_owned()]);
+        // Defaults from the usage
+        assert_eq!(args.flag_backup_suffix, ".bak");
+        assert_eq!(args.flag_buffer_size, 512);
+        assert_eq!(args.flag_bom, "keep");
+        assert_eq!(args.arg_input, None);
+        assert_eq!(args.flag_report_fd, None);
+    }
+
+    #[test]
+    fn convert_command_is_optional() {
+        let forms : [&[&str]; 2] = [&["convert", "--output-dir=out", "a.tex"], 
+            &["--output-dir=out", "a.tex"]];
+        for argv in &forms {
+            let args = testing::args(argv);
+            assert_eq!(args.cmd_convert, argv[0] == "convert");
+            assert_eq!(args.flag_output_dir, Some("out".to_owned()));
+            assert_eq!(args.arg_file, vec!["a.tex".to_owned()]);
+        }
+        let args = testing::args(&["doc.tex", "--stdout"]);
+        assert!(!args.cmd_convert && args.flag_stdout);
+        assert_eq!(args.arg_input, Some("doc.tex".to_owned()));
+    }
+
+    #[test]
+    fn invalid_option_values_are_invalid_arguments() {
+        let minor = usage_minor(Configuration::from_args(testing::args(
+            &["--no-config", "--builtin=greek", "--jobs=0", "-", "--stdout"])));
+        assert_eq!(minor, error::code::usage::INVALID_ARGUMENT);
+        let minor = usage_minor(Configuration::from_args(testing::args(
+            &["--no-config", "--builtin=klingon", "-", "--stdout"])));
+        assert_eq!(minor, error::code::usage::INVALID_ARGUMENT);
+        let minor = usage_minor(Configuration::from_args(testing::args(
+            &["--no-config", "--builtin=greek", "--bom=remove", "-", "--stdout"])));
+        assert_eq!(minor, error::code::usage::INVALID_ARGUMENT);
+    }
+
+    /// Accepts everything, but fails to flush it.
+    struct UnflushableWriter;
+
+    impl Write for UnflushableWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Err(io::Error::new(io::ErrorKind::Other, "disk full"))
+        }
+    }
+
+    fn is_tracked(tmp_path: &Path) -> bool {
+        TEMP_FILES.lock().unwrap().iter().any(|path| path == tmp_path)
+    }
+
+    #[test]
+    fn in_place_close_moves_the_temp_file_into_place() {
+        let dir = TempDir::new();
+        let dest = dir.write("doc.tex", b"\\alpha");
+        let output = Output::InPlace(dest.clone(), dir.path().join(".doc.tex.tmp"), None);
+        let mut file = output.open().unwrap();
+        file.write_all("α".as_bytes()).unwrap();
+        output.close(file).unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), "α".as_bytes());
+        assert!(!dir.path().join(".doc.tex.tmp").exists());
+    }
+
+    #[test]
+    fn failed_close_removes_the_temp_file() {
+        let dir = TempDir::new();
+        let dest = dir.write("doc.tex", b"\\alpha");
+        let tmp_path = dir.path().join(".doc.tex.tmp");
+        let output = Output::InPlace(dest.clone(), tmp_path.clone(), None);
+        ::std::mem::drop(output.open().unwrap());
+        assert!(tmp_path.exists() && is_tracked(&tmp_path));
+
+        assert!(output.close(Box::new(UnflushableWriter)).is_err());
+        assert!(!tmp_path.exists());
+        assert!(!is_tracked(&tmp_path));
+        assert_eq!(fs::read(&dest).unwrap(), b"\\alpha");
+    }
+
+    #[test]
+    fn failed_conversion_keeps_the_previous_backup() {
+        let dir = TempDir::new();
+        let dest = dir.write("doc.tex", b"\\alpha \\beta");
+        let previous_backup = dir.write("doc.tex.bak", b"\\alpha");
+        let backup = Backup::new(".bak".to_owned(), None).unwrap();
+        let output = Output::InPlace(dest.clone(), dir.path().join(".doc.tex.tmp"), Some(backup));
+        let mut file = output.open().unwrap();
+        file.write_all("α".as_bytes()).unwrap();
+        ::std::mem::drop(file);
+
+        assert!(output.close(Box::new(UnflushableWriter)).is_err());
+        assert_eq!(fs::read(&dest).unwrap(), b"\\alpha \\beta");
+        assert_eq!(fs::read(&previous_backup).unwrap(), b"\\alpha");
+
+        let mut file = output.open().unwrap();
+        file.write_all("α β".as_bytes()).unwrap();
+        output.close(file).unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), "α β".as_bytes());
+        assert_eq!(fs::read(&previous_backup).unwrap(), b"\\alpha \\beta");
+    }
+
+    #[test]
+    fn backups_are_rotated_from_newest_to_oldest() {
+        let dir = TempDir::new();
+        let dest = dir.write("doc.tex", b"0");
+        let backup = Backup::new(".bak".to_owned(), None).unwrap().with_keep(3).unwrap();
+        let output = Output::InPlace(dest.clone(), dir.path().join(".doc.tex.tmp"), Some(backup));
+        for version in 1 .. 5 {
+            let mut file = output.open().unwrap();
+            file.write_all(version.to_string().as_bytes()).unwrap();
+            output.close(file).unwrap();
+        }
+        assert_eq!(fs::read(&dest).unwrap(), b"4");
+        let backup = |number: usize| fs::read(dir.path().join(format!("doc.tex.bak.{}", number)))
+            .unwrap();
+        assert_eq!((backup(1), backup(2), backup(3)), 
+            (b"3".to_vec(), b"2".to_vec(), b"1".to_vec()));
+        assert!(!dir.path().join("doc.tex.bak.4").exists());
+        assert!(!dir.path().join("doc.tex.bak").exists());
+    }
+
+    #[test]
+    fn existing_output_is_only_overwritten_with_force() {
+        let dir = TempDir::new();
+        let output_path = dir.write("out.tex", b"previous");
+        let output = output_path.to_str().unwrap();
+        let minor = usage_minor(Configuration::from_args(testing::args(
+            &["--no-config", "--builtin=greek", "-", output])));
+        assert_eq!(minor, error::code::usage::OUTPUT_EXISTS);
+        Configuration::from_args(testing::args(
+            &["--no-config", "--builtin=greek", "--force", "-", output])).unwrap();
+
+        // Created after the check, e.g., by another process
+        let new_path = dir.path().join("new.tex");
+        let target = Output::OtherFile(new_path.clone(), false);
+        fs::write(&new_path, b"previous").unwrap();
+        assert_eq!(usage_minor(target.open()), error::code::usage::OUTPUT_EXISTS);
+        assert_eq!(fs::read(&new_path).unwrap(), b"previous");
+        let mut file = Output::OtherFile(new_path.clone(), true).open().unwrap();
+        file.write_all(b"converted").unwrap();
+        ::std::mem::drop(file);
+        assert_eq!(fs::read(&new_path).unwrap(), b"converted");
+    }
+
+    #[test]
+    fn show_config_lists_the_searched_directories() {
+        let _guard = testing::lock_env();
+        let dir = TempDir::new();
+        dir.write("to-uni.yml", "patterns:\n  alpha: α\n".as_bytes());
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        let input = dir.write("sub/doc.tex", b"\\alpha");
+        let config = Configuration::from_args(testing::args(
+            &["--show-config", input.to_str().unwrap(), "--stdout"])).unwrap();
+        assert_eq!(config.config_search_path, 
+            vec![dir.path().join("sub"), dir.path().to_path_buf()]);
+
+        let mut summary = Vec::new();
+        config.write_summary(&mut summary).unwrap();
+        let summary = String::from_utf8(summary).unwrap();
+        assert!(summary.contains(&format!("Searched directories:\n    {}\n    {}\n", 
+            dir.path().join("sub").display(), dir.path().display())), "{}", summary);
+    }
+
+    /// Peak resident set size of the process in kB.
+    #[cfg(target_os = "linux")]
+    fn peak_rss_kb() -> u64 {
+        let status = fs::read_to_string("/proc/self/status").unwrap();
+        status.lines().find(|line| line.starts_with("VmHWM:"))
+            .and_then(|line| line.split_whitespace().nth(1))
+            .and_then(|kb| kb.parse().ok())
+            .expect("VmHWM should be listed in /proc/self/status")
+    }
+
+    /// Measures the memory needed to load a 10 MB configuration file. Run it on its own 
+    /// (`cargo test -- --ignored --test-threads=1 large_config`), since the peak covers the whole 
+    /// process.
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[ignore]
+    fn large_config_memory() {
+        let dir = TempDir::new();
+        let mut text = String::from("patterns:\n");
+        let mut i = 0;
+        while text.len() < 10 * 1024 * 1024 {
+            text.push_str(&format!("  key{}: \"value {}\"\n", i, i));
+            i += 1;
+        }
+        let path = dir.write("to-uni.yml", text.as_bytes());
+        let size_kb = text.len() as u64 / 1024;
+        ::std::mem::drop(text);
+
+        let before = peak_rss_kb();
+        let raw_config = Configuration::read_config_file(&mut File::open(&path).unwrap(), &path, 
+            None).unwrap();
+        let growth = peak_rss_kb().saturating_sub(before);
+        println!("Loading {} kB of configuration ({} patterns) raised the peak by {} kB ({:.1}x)",
+            size_kb, i, growth, growth as f64 / size_kb as f64);
+        assert_eq!(raw_config["patterns"].as_hash().map(|patterns| patterns.len()), Some(i));
     }
 }
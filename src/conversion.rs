@@ -1,63 +1,2035 @@
 
 extern crate stopwatch;
 
+use std::cmp::{max, min};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufRead, BufWriter, Read, Write};
+use std::fs::{self, File};
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
 use ::common::*;
-use ::config::Configuration;
-use ::error::{UniError,code, UniErrorData};
+use ::config::{Configuration, Input, MatchLog, Output, Target};
+use ::error::{self, UniError,code, UniErrorData};
 
 use ::aho_corasick::{AcAutomaton,Automaton};
-use ::aho_corasick::chunked::{StreamChunks,StreamChunk,StreamChunkError};
+
+use ::memmap::Mmap;
+
+use ::flate2::Compression;
+use ::flate2::read::GzDecoder;
+use ::flate2::write::GzEncoder;
+
+use ::regions::{RegionFilter, RegionSettings};
+use ::normalization::{LineEndingConverter, LineEndings, Normalization, Normalizer};
+use ::translit::Transliteration;
+use ::format::OutputFormat;
+use ::encoding::{BOM, BomMode, DecodingReader, OutputEncoder, Utf8Validator, strip_bom};
+use ::encoding_rs::Encoding;
+use ::progress::ProgressReader;
 
 use self::stopwatch::Stopwatch;
 
-/// Performs substitution on a single input stream according to the supplied configuration.
+/// Matching automaton together with the replacement for each of its patterns. A converter can be 
+/// built once and then applied to any number of streams.
+pub struct Converter {
+    automaton: AcAutomaton<String>,
+    /// Replacement text, indexed by pattern index of the automaton.
+    lookup_map: Vec<String>,
+    /// Key of the pattern table each pattern index stands for, for reports.
+    keys: Vec<String>,
+    /// Pattern indices of the escaped prefixes (e.g., `\\`), which are passed through unchanged.
+    escape_patis: Vec<usize>,
+    /// Size of the buffer used when streaming the input.
+    buffer_size: usize,
+    /// Condition for replacing an occurrence of a pattern.
+    match_mode: MatchMode,
+    /// Whether a single space following a replaced pattern is removed.
+    consume_trailing_space: bool,
+    /// Regions of the input in which patterns are left alone.
+    regions: RegionSettings,
+    /// Unicode normalization form of the output, if any.
+    normalization: Option<Normalization>,
+    /// Line endings of the output.
+    line_endings: LineEndings,
+    /// Character encoding of the input, if not UTF-8.
+    input_encoding: Option<&'static Encoding>,
+    /// Character encoding of the output, if not UTF-8.
+    output_encoding: Option<&'static Encoding>,
+    /// What happens to a byte order mark at the start of the input.
+    bom: BomMode,
+    /// Pattern indices whose replacement has no ASCII transliteration, with the offending 
+    /// character. Replacing any of them fails the conversion.
+    untransliterable: HashMap<usize, char>,
+    /// Whether each replacement is printed to stderr along with its position in the input.
+    trace: bool,
+    /// Whether input that is not valid UTF-8 fails the conversion.
+    check_utf8: bool,
+    /// Whether each replacement needs to be confirmed on standard input.
+    interactive: bool,
+    /// Whether replacements are highlighted with ANSI escape codes.
+    color: bool,
+    /// Number of replacements after which all further occurrences are kept, over all streams.
+    max_replacements: Option<usize>,
+    /// Number of replacements performed so far, counting towards `max_replacements`.
+    replacements: AtomicUsize,
+    /// The user answered "all" to a confirmation; the remaining replacements are performed 
+    /// without asking.
+    confirmed_all: AtomicBool,
+    /// The user answered "quit" to a confirmation.
+    quit: AtomicBool,
+    /// Where a NUL-separated record of each replacement is written to (--print0-report), shared 
+    /// by all streams.
+    match_log: Option<Mutex<BufWriter<Box<Write + Send>>>>,
+    /// Length of the longest pattern (including the `\` prefix), computed when the automaton is 
+    /// built. The streaming buffer must never be smaller, see `Converter::stream_capacity`.
+    min_buffer_size: usize
+}
+
+/// Default size of the buffer used when streaming the input.
+pub const DEFAULT_BUFFER_SIZE: usize = 512;
+
+/// Name of the input in the match log for conversions via `convert`, which don't know it.
+const UNNAMED_INPUT: &'static str = "<input>";
+
+/// The LaTeX escape character, which starts every pattern unless configured otherwise.
+pub const DEFAULT_PREFIX: &'static str = "\\";
+
+/// Text surrounding a key in the input, e.g., `\` and nothing for `\alpha` or `:` and `:` for 
+/// `:alpha:`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Marker {
+    pub prefix: String,
+    pub suffix: String
+}
+
+impl Marker {
+    pub fn new(prefix: &str, suffix: &str) -> Marker {
+        Marker { prefix: prefix.to_owned(), suffix: suffix.to_owned() }
+    }
+
+    /// The text `key` appears as in the input.
+    fn wrap(&self, key: &str) -> String {
+        format!("{}{}{}", self.prefix, key, self.suffix)
+    }
+}
+
+impl Default for Marker {
+    fn default() -> Marker {
+        Marker::new(DEFAULT_PREFIX, "")
+    }
+}
+
+impl Converter {
+    /// Creates a converter that replaces `\key` with the corresponding value. Escaped 
+    /// backslashes are recognized, so `\\key` is left alone.
+    pub fn new(patterns: &BTreeMap<String, String>) -> Converter {
+        Converter::with_prefix(patterns, DEFAULT_PREFIX)
+    }
+
+    /// Creates a converter that replaces `<prefix>key` with the corresponding value. Just like 
+    /// `\\` in LaTeX, a doubled prefix is passed through unchanged and the text following it is 
+    /// not a pattern. With an empty prefix, bare keys are replaced.
+    pub fn with_prefix(patterns: &BTreeMap<String, String>, prefix: &str) -> Converter {
+        Converter::with_markers(patterns, &[Marker::new(prefix, "")])
+    }
+
+    /// Creates a converter that replaces each key surrounded by any of the `markers` with the 
+    /// corresponding value. Doubled prefixes are passed through unchanged, see `with_prefix`.
+    pub fn with_markers(patterns: &BTreeMap<String, String>, markers: &[Marker]) -> Converter {
+        let escapes : Vec<String> = markers.iter()
+            .filter(|marker| !marker.prefix.is_empty())
+            .map(|marker| marker.prefix.repeat(2))
+            .collect();
+        let entries = markers.iter()
+            .flat_map(|marker| patterns.iter()
+                .map(move |(k,v)| (marker.wrap(k), v.clone(), k.clone())))
+            .chain(escapes.iter().map(|e| (e.clone(), e.clone(), e.clone())));
+        Converter::from_entries(entries, &escapes)
+    }
+
+    /// Creates a converter that replaces each value with `\key`. The values are expected to be 
+    /// unique.
+    pub fn reverse(patterns: &BTreeMap<String, String>) -> Converter {
+        Converter::reverse_with_marker(patterns, &Marker::default())
+    }
+
+    /// Creates a converter that replaces each value with its key surrounded by `marker`. The 
+    /// values are expected to be unique.
+    pub fn reverse_with_marker(patterns: &BTreeMap<String, String>, marker: &Marker) -> Converter {
+        let entries = patterns.iter().map(|(k,v)| (v.clone(), marker.wrap(k), k.clone()));
+        Converter::from_entries(entries, &[])
+    }
+
+    /// Builds the automaton from (pattern, replacement, key) triples. The replacements end up in 
+    /// the same order as the patterns, so a pattern index maps straight to its replacement.
+    fn from_entries<I: Iterator<Item=(String, String, String)>>(entries: I, escapes: &[String]) 
+            -> Converter {
+        let mut needles = Vec::new();
+        let mut lookup_map = Vec::new();
+        let mut keys = Vec::new();
+        for (needle, replacement, key) in prioritized(entries) {
+            needles.push(needle);
+            lookup_map.push(replacement);
+            keys.push(key);
+        }
+        let escape_patis = needles.iter().enumerate()
+            .filter(|&(_, n)| escapes.contains(n))
+            .map(|(pati, _)| pati)
+            .collect();
+        let min_buffer_size = needles.iter().map(|n| n.len()).max().unwrap_or(0);
+        let automaton = AcAutomaton::new(needles);
+        Converter { 
+            automaton, lookup_map, keys, escape_patis, min_buffer_size,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            match_mode: MatchMode::Raw,
+            consume_trailing_space: false,
+            regions: RegionSettings::default(),
+            normalization: None,
+            line_endings: LineEndings::Preserve,
+            input_encoding: None,
+            output_encoding: None,
+            bom: BomMode::Keep,
+            untransliterable: HashMap::new(),
+            trace: false,
+            check_utf8: false,
+            interactive: false,
+            color: false,
+            max_replacements: None,
+            replacements: AtomicUsize::new(0),
+            confirmed_all: AtomicBool::new(false),
+            quit: AtomicBool::new(false),
+            match_log: None
+        }
+    }
+
+    /// Only replaces occurrences that satisfy `match_mode`. 
+    pub fn with_match_mode(mut self, match_mode: MatchMode) -> Converter {
+        self.match_mode = match_mode;
+        self
+    }
+
+    /// Removes a single space following a replaced pattern, so that `\alpha x` becomes `αx`. 
+    pub fn with_trailing_space_consumed(mut self, consume_trailing_space: bool) -> Converter {
+        self.consume_trailing_space = consume_trailing_space;
+        self
+    }
+
+    /// Leaves patterns alone in the given regions of the input.
+    pub fn with_regions(mut self, regions: RegionSettings) -> Converter {
+        self.regions = regions;
+        self
+    }
+
+    /// Normalizes the output to the given Unicode normalization form. Without this, the output 
+    /// is byte-for-byte the input with the replacements applied.
+    pub fn with_normalization(mut self, form: Normalization) -> Converter {
+        self.normalization = Some(form);
+        self
+    }
+
+    /// Writes all line endings as `line_endings`. By default, they are preserved.
+    pub fn with_line_endings(mut self, line_endings: LineEndings) -> Converter {
+        self.line_endings = line_endings;
+        self
+    }
+
+    /// Decodes the input from `encoding` instead of expecting UTF-8.
+    pub fn with_input_encoding(mut self, encoding: &'static Encoding) -> Converter {
+        self.input_encoding = Some(encoding);
+        self
+    }
+
+    /// Encodes the output in `encoding` instead of writing UTF-8. Characters that the encoding 
+    /// lacks fail the conversion.
+    pub fn with_output_encoding(mut self, encoding: &'static Encoding) -> Converter {
+        self.output_encoding = Some(encoding);
+        self
+    }
+
+    /// Removes, keeps or adds a byte order mark at the start of the output.
+    pub fn with_bom(mut self, bom: BomMode) -> Converter {
+        self.bom = bom;
+        self
+    }
+
+    /// Replaces each pattern with an ASCII approximation of its replacement instead.
+    pub fn with_transliteration(mut self, translit: &Transliteration) -> Converter {
+        for pati in 0 .. self.lookup_map.len() {
+            if self.escape_patis.contains(&pati) {
+                continue;
+            }
+            match translit.apply(&self.lookup_map[pati]) {
+                Ok(ascii) => self.lookup_map[pati] = ascii,
+                Err(c) => { self.untransliterable.insert(pati, c); }
+            }
+        }
+        self
+    }
+
+    /// Writes the replacements in the given format, e.g., as numeric character references.
+    pub fn with_output_format(mut self, format: OutputFormat) -> Converter {
+        for pati in 0 .. self.lookup_map.len() {
+            if !self.escape_patis.contains(&pati) {
+                self.lookup_map[pati] = format.apply(&self.lookup_map[pati]);
+            }
+        }
+        self
+    }
+
+    /// Prints each replacement to stderr together with its line, column and byte offset in the 
+    /// (decoded) input.
+    pub fn with_trace(mut self, trace: bool) -> Converter {
+        self.trace = trace;
+        self
+    }
+
+    /// Fails the conversion at the first byte sequence of the (decoded) input that is not valid 
+    /// UTF-8 instead of passing it through.
+    pub fn with_utf8_check(mut self, check_utf8: bool) -> Converter {
+        self.check_utf8 = check_utf8;
+        self
+    }
+
+    /// Asks on standard error whether to perform each replacement and reads the answer from 
+    /// standard input, which therefore cannot be the input of the conversion.
+    pub fn with_confirmation(mut self, interactive: bool) -> Converter {
+        self.interactive = interactive;
+        self
+    }
+
+    /// Highlights each replacement with ANSI escape codes. Meant for output to a terminal.
+    pub fn with_color(mut self, color: bool) -> Converter {
+        self.color = color;
+        self
+    }
+
+    /// Stops replacing after `max` replacements, summed over all streams this converter is 
+    /// applied to. The remaining occurrences are passed through unchanged.
+    pub fn with_max_replacements(mut self, max: Option<usize>) -> Converter {
+        self.max_replacements = max;
+        self
+    }
+
+    /// Writes a record of each replacement to `log`: the name of the input, the byte offset in 
+    /// the (decoded) input, the pattern and the replacement, each terminated by a NUL byte. The log needs to be flushed 
+    /// with `flush_match_log` once all streams are converted.
+    pub fn with_match_log(mut self, log: Box<Write + Send>) -> Converter {
+        self.match_log = Some(Mutex::new(BufWriter::new(log)));
+        self
+    }
+
+    /// Writes the buffered records of the match log, if there is one.
+    pub fn flush_match_log(&self) -> io::Result<()> {
+        match self.match_log {
+            Some(ref log) => log.lock().expect("Match log should not be poisoned.").flush(),
+            None => Ok(())
+        }
+    }
+
+    /// Whether no more replacements are allowed (see `with_max_replacements`).
+    pub fn replacement_limit_reached(&self) -> bool {
+        self.max_replacements.map_or(false, |max| self.replacements.load(Ordering::SeqCst) >= max)
+    }
+
+    /// Counts a replacement towards the limit. Returns false if the limit has been reached.
+    fn claim_replacement(&self) -> bool {
+        match self.max_replacements {
+            Some(max) => self.replacements.fetch_add(1, Ordering::SeqCst) < max,
+            None => true
+        }
+    }
+
+    /// Whether the user quit while being asked to confirm a replacement. The conversion of the 
+    /// current input has failed then; no further inputs should be converted.
+    pub fn quit_requested(&self) -> bool {
+        self.quit.load(Ordering::SeqCst)
+    }
+
+    /// Whether replacing the pattern with index `pati` depends on the text following it.
+    fn needs_lookahead(&self, pati: usize) -> bool {
+        match self.match_mode {
+            MatchMode::Raw => false,
+            MatchMode::WordBoundary => self.automaton.pattern(pati).as_bytes().last()
+                .map_or(false, |b| b.is_ascii_alphabetic()),
+            MatchMode::Braces => true
+        }
+    }
+
+    /// Uses a buffer of `buffer_size` bytes when streaming the input. The buffer should be at 
+    /// least as large as the longest pattern.
+    pub fn with_buffer_size(mut self, buffer_size: usize) -> Converter {
+        self.buffer_size = buffer_size;
+        self
+    }
+
+    /// Length of the longest pattern in bytes.
+    pub fn longest_pattern(&self) -> usize {
+        self.min_buffer_size
+    }
+
+    /// Whether the conversion changes text that no pattern matches, e.g., its line endings. If 
+    /// not, an input without replacements comes out exactly as it went in.
+    pub fn rewrites_unmatched_text(&self) -> bool {
+        self.normalization.is_some() || self.line_endings != LineEndings::Preserve 
+            || self.input_encoding.is_some() || self.output_encoding.is_some() 
+            || self.bom != BomMode::Keep
+    }
+
+    /// Number of bytes read at a time when streaming. The window the automaton runs over also 
+    /// retains the bytes that might still be the start of an occurrence, so an occurrence that 
+    /// straddles the boundary between two reads is still recognized. Reading at least as many 
+    /// bytes as the longest pattern keeps the window from being mostly undecided leftovers.
+    fn stream_capacity(&self) -> usize {
+        max(self.buffer_size, self.min_buffer_size)
+    }
+
+    /// Streams `input` to `output`, replacing every pattern occurrence along the way. Returns the 
+    /// number of replacements performed.
+    ///
+    /// The automaton works on bytes and the input is read in pieces of `buffer_size` bytes, but 
+    /// valid UTF-8 comes out as valid UTF-8, identical to a conversion in a single pass: patterns 
+    /// are valid UTF-8 themselves and thus can only match at character boundaries, replacements 
+    /// are written as a whole, and text between matches is passed on byte for byte regardless of 
+    /// where a piece ends. The output transformations (normalization, encoding) hold back 
+    /// incomplete characters until the rest arrives.
+    pub fn convert<R: Read, W: Write>(&self, input: R, output: &mut W) -> UniResult<u64> {
+        self.process(input, output, UNNAMED_INPUT, None).map(|stats| stats.matches)
+    }
+
+    /// Converts `input`, which is called `name` in the records of the match log.
+    fn process<R: Read, W: Write>(&self, input: R, output: &mut W, name: &str, 
+            unmatched: Option<&mut UnmatchedTokens>) -> UniResult<ConversionStats> {
+        match self.input_encoding {
+            Some(encoding) => 
+                self.process_utf8(DecodingReader::new(input, encoding), output, name, unmatched),
+            None => self.process_utf8(input, output, name, unmatched)
+        }
+    }
+
+    fn process_utf8<R: Read, W: Write>(&self, input: R, output: &mut W, name: &str, 
+            unmatched: Option<&mut UnmatchedTokens>) -> UniResult<ConversionStats> {
+        match self.bom {
+            BomMode::Keep => self.process_text(input, output, name, unmatched),
+            BomMode::Strip | BomMode::Add => {
+                let input = strip_bom(input).map_err(|e| UniError::from_io(code::fsio::INPUT, e))?;
+                self.process_text(input, output, name, unmatched)
+            }
+        }
+    }
+
+    /// Streams `input` through a window that the matches are selected from just like for the 
+    /// memory-mapped conversion (see `leftmost_longest`). A match is only passed on once it 
+    /// starts far enough from the end of the window that no longer match at the same position 
+    /// (and no match starting before it) can still show up; the rest of the window is kept until 
+    /// more input arrives. That way, `\alphabeta` isn't mistaken for `\alpha` just because 
+    /// `\alpha` is complete first.
+    fn process_text<R: Read, W: Write>(&self, mut input: R, output: &mut W, name: &str, 
+            unmatched: Option<&mut UnmatchedTokens>) -> UniResult<ConversionStats> {
+        let mut session = Session::new(self, output, name, unmatched)?;
+        let capacity = self.stream_capacity();
+        let max_len = self.longest_pattern();
+        let mut window : Vec<u8> = Vec::with_capacity(capacity + max_len);
+        let mut at_end = false;
+        while !at_end {
+            let filled = window.len();
+            window.resize(filled + capacity, 0);
+            let read = loop {
+                match input.read(&mut window[filled ..]) {
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    result => break result
+                }
+            }.map_err(|e| UniError::from_io(code::fsio::INPUT, e))?;
+            window.truncate(filled + read);
+            at_end = read == 0;
+            // Matches starting before `decided` are complete, and no more input can change them
+            let decided = if at_end {
+                window.len()
+            } else {
+                min(window.len(), (window.len() + 1).saturating_sub(max_len))
+            };
+            let mut last = 0;
+            for (pati, start, end) in self.leftmost_longest(&window) {
+                if start >= decided {
+                    break;
+                }
+                session.text(&window[last .. start])?;
+                session.matched(pati)?;
+                last = end;
+            }
+            let done = max(last, decided);
+            session.text(&window[last .. done])?;
+            window.drain(.. done);
+        }
+        session.finish()
+    }
+
+    /// Memory-maps the input file and runs the automaton over the whole file in one pass instead 
+    /// of streaming it in chunks.
+    fn process_mapped<W: Write>(&self, path: &Path, output: &mut W, name: &str, 
+            unmatched: Option<&mut UnmatchedTokens>) -> UniResult<ConversionStats> {
+        let file = try_!(File::open(path), path.to_string_lossy().into_owned(), code::fsio::INPUT);
+        let len = try_!(file.metadata(), path.to_string_lossy().into_owned(), 
+            code::fsio::INPUT).len();
+        if len == 0 || self.input_encoding.is_some() {
+            // Empty files cannot be mapped (and there is nothing to gain anyway). Other encodings 
+            // need to be decoded while streaming.
+            return self.process(file, output, name, unmatched);
+        }
+        // The mapping is only valid as long as nobody else modifies the file. That is the same 
+        // assumption an in-place conversion makes.
+        let map = try_!(unsafe { Mmap::map(&file) }, path.to_string_lossy().into_owned(), 
+            code::fsio::INPUT);
+        debug!("Mapped {} bytes of {}", len, path.display());
+
+        let text = if self.bom != BomMode::Keep && map.starts_with(BOM) {
+            debug!("Removing byte order mark");
+            &map[BOM.len() ..]
+        } else {
+            &map[..]
+        };
+
+        let mut session = Session::new(self, output, name, unmatched)?;
+        let mut last = 0;
+        for (pati, start, end) in self.leftmost_longest(text) {
+            session.text(&text[last .. start])?;
+            session.matched(pati)?;
+            last = end;
+        }
+        session.text(&text[last ..])?;
+        session.finish()
+    }
+
+    /// Finds non-overlapping matches in `haystack`: the leftmost match wins, among those the 
+    /// longest one. Returns pattern index, start and end of each match. Both the memory-mapped 
+    /// and the streaming conversion select their matches this way, so they produce the same 
+    /// output.
+    fn leftmost_longest(&self, haystack: &[u8]) -> Vec<(usize, usize, usize)> {
+        let max_len = self.longest_pattern();
+        let mut selected = Vec::new();
+        let mut candidates : Vec<(usize, usize, usize)> = Vec::new();
+        // End of the last selected match
+        let mut pos = 0;
+        for m in self.automaton.find_overlapping(haystack) {
+            // Overlapping matches are reported in order of their end. Once a match ends more than 
+            // max_len bytes after the start of the best candidate, no match starting at or before 
+            // that candidate can show up anymore.
+            while let Some(best) = Converter::best_candidate(&candidates) {
+                if m.end <= best.1 + max_len {
+                    break;
+                }
+                selected.push(best);
+                pos = best.2;
+                candidates.retain(|c| c.1 >= pos);
+            }
+            if m.start >= pos {
+                candidates.push((m.pati, m.start, m.end));
+            }
+        }
+        while let Some(best) = Converter::best_candidate(&candidates) {
+            selected.push(best);
+            pos = best.2;
+            candidates.retain(|c| c.1 >= pos);
+        }
+        selected
+    }
+
+    fn best_candidate(candidates: &[(usize, usize, usize)]) -> Option<(usize, usize, usize)> {
+        // Leftmost first, then longest, then in the order of the automaton's patterns
+        candidates.iter().cloned().min_by(|a, b| a.1.cmp(&b.1)
+            .then_with(|| b.2.cmp(&a.2))
+            .then_with(|| a.0.cmp(&b.0)))
+    }
+}
+
+/// Condition an occurrence of a pattern has to satisfy in order to be replaced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchMode {
+    /// Every occurrence is replaced
+    Raw,
+    /// Patterns ending in a letter are only replaced if no letter follows, so `\alpha` is left 
+    /// alone in `\alphanumeric`
+    WordBoundary,
+    /// Patterns are only replaced if followed by `{}`, which is removed along with them
+    Braces
+}
+
+impl MatchMode {
+    /// Parses the name used on the command line and in configuration files.
+    pub fn from_name(name: &str) -> Option<MatchMode> {
+        match name {
+            "raw" => Some(MatchMode::Raw),
+            "word-boundary" => Some(MatchMode::WordBoundary),
+            "braces" => Some(MatchMode::Braces),
+            _ => None
+        }
+    }
+}
+
+impl Default for MatchMode {
+    fn default() -> MatchMode {
+        MatchMode::Raw
+    }
+}
+
+/// A position in the input, for diagnostics.
+#[derive(Debug, Default, Clone, Copy)]
+struct Position {
+    /// Bytes before this position
+    offset: u64,
+    /// Zero-based line number
+    line: usize,
+    /// Zero-based column, counted in characters
+    column: usize
+}
+
+impl Position {
+    /// Moves past `bytes`. The bytes need not end at a character boundary.
+    fn advance(&mut self, bytes: &[u8]) {
+        self.offset += bytes.len() as u64;
+        for &b in bytes {
+            if b == b'\n' {
+                self.line += 1;
+                self.column = 0;
+            } else if b & 0xC0 != 0x80 {
+                // Continuation bytes don't start a new character
+                self.column += 1;
+            }
+        }
+    }
+}
+
+/// ANSI escape codes around replacements with `color` (bold green).
+const HIGHLIGHT_START: &'static [u8] = b"\x1b[1;32m";
+const HIGHLIGHT_END: &'static [u8] = b"\x1b[0m";
+
+/// Number of bytes preceding a match that are shown when asking for confirmation.
+const MAX_CONTEXT: usize = 40;
+
+/// A match whose replacement depends on the text following it (see `MatchMode`).
+struct PendingMatch {
+    pati: usize,
+    /// Where the match starts in the input
+    position: Position,
+    /// Bytes following the match that have been held back while deciding, e.g., the `{` of `{}`
+    held: Vec<u8>
+}
+
+/// State of a single conversion. The pieces of the input need to be fed in order.
+struct Session<'a, W: Write + 'a> {
+    converter: &'a Converter,
+    output: &'a mut W,
+    /// Name of the input in the records of the match log
+    name: &'a str,
+    unmatched: Option<&'a mut UnmatchedTokens>,
+    /// Match waiting for the following bytes. This can span several chunks of the input.
+    pending: Option<PendingMatch>,
+    /// Whether a space at the start of the next chunk of text is to be dropped.
+    swallow_space: bool,
+    /// Trackers for the regions in which patterns are left alone.
+    regions: Vec<Box<RegionFilter>>,
+    line_endings: Option<LineEndingConverter>,
+    normalizer: Option<Normalizer>,
+    encoder: Option<OutputEncoder>,
+    utf8_validator: Option<Utf8Validator>,
+    /// The last few bytes of the input, shown when asking for confirmation. Only kept for 
+    /// `interactive`.
+    context: Vec<u8>,
+    /// Position of the next byte of the input. Only tracked for `trace`, `interactive` and the 
+    /// match log.
+    position: Position,
+    /// Number of replacements per pattern index
+    pattern_matches: Vec<u64>,
+    stats: ConversionStats
+}
+
+impl<'a, W: Write + 'a> Session<'a, W> {
+    fn new(converter: &'a Converter, output: &'a mut W, name: &'a str, 
+            unmatched: Option<&'a mut UnmatchedTokens>) -> UniResult<Session<'a, W>> {
+        let stats = ConversionStats { files: 1, .. Default::default() };
+        let regions = converter.regions.filters();
+        let line_endings = match converter.line_endings {
+            LineEndings::Preserve => None,
+            target => Some(LineEndingConverter::new(target))
+        };
+        let normalizer = converter.normalization.map(Normalizer::new);
+        let encoder = match converter.output_encoding {
+            Some(encoding) => Some(OutputEncoder::new(encoding)?),
+            None => None
+        };
+        let utf8_validator = if converter.check_utf8 { Some(Utf8Validator::new()) } else { None };
+        let pattern_matches = vec![0; converter.keys.len()];
+        let mut session = Session { converter, output, name, unmatched, pending: None, 
+            swallow_space: false, regions, line_endings, normalizer, encoder, utf8_validator, 
+            context: Vec::new(), position: Position::default(), pattern_matches, stats };
+        if converter.bom == BomMode::Add {
+            session.write(BOM)?;
+        }
+        Ok(session)
+    }
+
+    /// Handles bytes that did not match any pattern.
+    fn text(&mut self, bytes: &[u8]) -> UniResult<()> {
+        debug!("Forwarding {} non-matching bytes.", bytes.len());
+        self.stats.bytes_in += bytes.len() as u64;
+        self.validate_utf8(bytes)?;
+        self.advance_regions(bytes);
+        self.advance_position(bytes);
+        self.advance_context(bytes);
+        let mut bytes = bytes;
+        while self.pending.is_some() && !bytes.is_empty() {
+            if self.resolve_pending(Some(bytes[0]), true)? {
+                bytes = &bytes[1..];
+            }
+        }
+        if self.swallow_space && !bytes.is_empty() {
+            self.swallow_space = false;
+            if bytes[0] == b' ' {
+                debug!("Removing space after replacement.");
+                bytes = &bytes[1..];
+            }
+        }
+        if let Some(ref mut u) = self.unmatched {
+            u.scan(bytes);
+        }
+        self.write(bytes)
+    }
+
+    /// Handles an occurrence of the pattern with index `pati`.
+    fn matched(&mut self, pati: usize) -> UniResult<()> {
+        let converter = self.converter;
+        let needle = converter.automaton.pattern(pati);
+        if self.pending.is_some() {
+            // The text of this match decides the previous one, but cannot become part of it
+            self.resolve_pending(needle.as_bytes().first().cloned(), false)?;
+        }
+        // Another match follows immediately, so there is no space to remove
+        self.swallow_space = false;
+        self.stats.bytes_in += needle.len() as u64;
+        // Patterns are valid UTF-8, but they might follow an incomplete character
+        self.validate_utf8(needle.as_bytes())?;
+        let position = self.position;
+        self.advance_position(needle.as_bytes());
+        self.advance_context(needle.as_bytes());
+
+        let active = self.regions_active();
+        self.advance_regions(needle.as_bytes());
+        if !active {
+            debug!("Found {} but keeping it (excluded region)", needle);
+            self.interrupt_unmatched();
+            return self.write(needle.as_bytes());
+        }
+        if converter.escape_patis.contains(&pati) {
+            self.interrupt_unmatched();
+            return self.write(converter.lookup_map[pati].as_bytes());
+        }
+
+        if converter.needs_lookahead(pati) {
+            self.pending = Some(PendingMatch { pati, position, held: Vec::new() });
+            Ok(())
+        } else {
+            self.replace(pati, position, &[])
+        }
+    }
+
+    /// Decides the pending match based on the byte following it (`None` at the end of the 
+    /// input). Returns whether that byte has been consumed as part of the match.
+    fn resolve_pending(&mut self, next: Option<u8>, consumable: bool) -> UniResult<bool> {
+        let mut pending = self.pending.take().expect("Should only be called with a pending match.");
+        match self.converter.match_mode {
+            MatchMode::Raw => self.replace(pending.pati, pending.position, &[]).map(|()| false),
+            MatchMode::WordBoundary => match next {
+                Some(b) if b.is_ascii_alphabetic() => self.keep(pending).map(|()| false),
+                _ => self.replace(pending.pati, pending.position, &[]).map(|()| false)
+            },
+            MatchMode::Braces => match (pending.held.len(), next) {
+                (0, Some(b'{')) if consumable => {
+                    pending.held.push(b'{');
+                    self.pending = Some(pending);
+                    Ok(true)
+                },
+                (1, Some(b'}')) if consumable => 
+                    self.replace(pending.pati, pending.position, b"{}").map(|()| true),
+                _ => self.keep(pending).map(|()| false)
+            }
+        }
+    }
+
+    /// Replaces an occurrence of the pattern with index `pati` that starts at `position`. 
+    /// `consumed` are the bytes following the pattern that are replaced along with it (e.g., 
+    /// `{}`); they are kept if the user declines the replacement.
+    fn replace(&mut self, pati: usize, position: Position, consumed: &[u8]) -> UniResult<()> {
+        self.interrupt_unmatched();
+        let converter = self.converter;
+        if converter.replacement_limit_reached() {
+            debug!("Keeping {} (--max-replacements reached)", converter.automaton.pattern(pati));
+            return self.keep_original(pati, consumed);
+        }
+        if converter.interactive && !self.confirm(pati, position)? {
+            debug!("Keeping {} (declined)", converter.automaton.pattern(pati));
+            return self.keep_original(pati, consumed);
+        }
+        // Several sessions might be racing for the last replacements
+        if !converter.claim_replacement() {
+            debug!("Keeping {} (--max-replacements reached)", converter.automaton.pattern(pati));
+            return self.keep_original(pati, consumed);
+        }
+        if let Some(&c) = converter.untransliterable.get(&pati) {
+            return Err(UniError::new(code::conversion::NO_TRANSLITERATION, 
+                UniErrorData::Conversion(format!(
+                    "No ASCII transliteration for {:?} (U+{:04X}) in the replacement of {}.", 
+                    c, c as u32, converter.automaton.pattern(pati)))));
+        }
+        self.stats.matches += 1;
+        self.pattern_matches[pati] += 1;
+        let replacement = &converter.lookup_map[pati];
+        if replacement.is_empty() {
+            debug!("Found {} deleting it (empty replacement)", converter.automaton.pattern(pati));
+        } else {
+            debug!("Found {} replacing it with {}", converter.automaton.pattern(pati), replacement);
+        }
+        if converter.trace {
+            let _ = writeln!(&mut io::stderr(), "line {} col {} (byte {}): {} -> {}", 
+                position.line + 1, position.column + 1, position.offset, 
+                converter.automaton.pattern(pati), replacement);
+        }
+        if let Some(ref log) = converter.match_log {
+            let mut log = log.lock().expect("Match log should not be poisoned.");
+            write!(log, "{}\0{}\0{}\0{}\0", self.name, position.offset, 
+                converter.automaton.pattern(pati), replacement)
+                .map_err(|e| UniError::from_io(code::fsio::OUTPUT, e))?;
+        }
+        self.swallow_space = converter.consume_trailing_space;
+        if converter.color && !replacement.is_empty() {
+            self.write(HIGHLIGHT_START)?;
+            self.write(replacement.as_bytes())?;
+            self.write(HIGHLIGHT_END)
+        } else {
+            self.write(replacement.as_bytes())
+        }
+    }
+
+    /// Writes an occurrence of the pattern with index `pati` and the bytes `consumed` along with 
+    /// it unchanged.
+    fn keep_original(&mut self, pati: usize, consumed: &[u8]) -> UniResult<()> {
+        let converter = self.converter;
+        self.write(converter.automaton.pattern(pati).as_bytes())?;
+        self.write(consumed)
+    }
+
+    /// Asks the user whether to replace the pattern with index `pati` that starts at `position`. 
+    /// Fails if the user quits (or standard input ends).
+    fn confirm(&mut self, pati: usize, position: Position) -> UniResult<bool> {
+        let converter = self.converter;
+        if converter.confirmed_all.load(Ordering::SeqCst) {
+            return Ok(true);
+        }
+        let line_start = self.context.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+        let context = String::from_utf8_lossy(&self.context[line_start ..]).into_owned();
+        let stdin = io::stdin();
+        loop {
+            let _ = write!(&mut io::stderr(), 
+                "line {} col {}: {}[{}] -> {}  Replace? [y]es/[n]o/[a]ll/[q]uit: ", 
+                position.line + 1, position.column + 1, context, converter.automaton.pattern(pati), 
+                converter.lookup_map[pati]);
+            let mut answer = String::new();
+            let read = try_!(stdin.lock().read_line(&mut answer), "<stdin>".to_string(), 
+                code::fsio::INPUT);
+            match answer.trim() {
+                "y" | "yes" => return Ok(true),
+                "n" | "no" => return Ok(false),
+                "a" | "all" => {
+                    converter.confirmed_all.store(true, Ordering::SeqCst);
+                    return Ok(true);
+                },
+                "q" | "quit" => break,
+                _ if read == 0 => break,
+                _ => ()
+            }
+        }
+        converter.quit.store(true, Ordering::SeqCst);
+        Err(UniError::new(code::conversion::QUIT, UniErrorData::Conversion(
+            "Stopped at the user's request (--interactive).".to_owned())))
+    }
+
+    /// Passes a match that doesn't satisfy the match mode through unchanged.
+    fn keep(&mut self, pending: PendingMatch) -> UniResult<()> {
+        let converter = self.converter;
+        let needle = converter.automaton.pattern(pending.pati);
+        debug!("Found {} but keeping it ({:?} mode)", needle, converter.match_mode);
+        if let Some(ref mut u) = self.unmatched {
+            u.interrupt();
+            u.scan(needle.as_bytes());
+            u.scan(&pending.held);
+        }
+        self.write(needle.as_bytes())?;
+        self.write(&pending.held)
+    }
+
+    fn validate_utf8(&mut self, bytes: &[u8]) -> UniResult<()> {
+        match self.utf8_validator {
+            Some(ref mut validator) => validator.push(bytes),
+            None => Ok(())
+        }
+    }
+
+    fn advance_position(&mut self, bytes: &[u8]) {
+        let converter = self.converter;
+        if converter.trace || converter.interactive || converter.match_log.is_some() {
+            self.position.advance(bytes);
+        }
+    }
+
+    fn advance_context(&mut self, bytes: &[u8]) {
+        if !self.converter.interactive {
+            return;
+        }
+        self.context.extend_from_slice(bytes);
+        if self.context.len() > MAX_CONTEXT {
+            let excess = self.context.len() - MAX_CONTEXT;
+            self.context.drain(.. excess);
+        }
+    }
+
+    /// Whether patterns are to be replaced at the current position.
+    fn regions_active(&self) -> bool {
+        self.regions.iter().all(|filter| filter.active())
+    }
+
+    /// Feeds input bytes to the region filters. A filter only sees the bytes that all filters 
+    /// before it consider active.
+    fn advance_regions(&mut self, bytes: &[u8]) {
+        if self.regions.is_empty() {
+            return;
+        }
+        for &b in bytes {
+            for filter in self.regions.iter_mut() {
+                filter.feed(b);
+                if !filter.active() {
+                    break;
+                }
+            }
+        }
+    }
+
+    fn interrupt_unmatched(&mut self) {
+        if let Some(ref mut u) = self.unmatched {
+            u.interrupt();
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> UniResult<()> {
+        self.write_through(bytes, false)
+    }
+
+    /// Passes text through the output transformations (line endings, normalization, encoding), 
+    /// each of which might hold back a few bytes. With `last`, the output ends here.
+    fn write_through(&mut self, bytes: &[u8], last: bool) -> UniResult<()> {
+        let mut line_ended = Vec::new();
+        let bytes = match self.line_endings {
+            Some(ref mut line_endings) => {
+                line_endings.push(bytes, &mut line_ended, last);
+                &line_ended[..]
+            },
+            None => bytes
+        };
+        let mut normalized = Vec::new();
+        let bytes = match self.normalizer {
+            Some(ref mut normalizer) => {
+                normalizer.push(bytes, &mut normalized);
+                if last {
+                    normalizer.finish(&mut normalized);
+                }
+                &normalized[..]
+            },
+            None => bytes
+        };
+        match self.encoder {
+            Some(ref mut encoder) => {
+                let mut encoded = Vec::with_capacity(bytes.len());
+                encoder.push(bytes, &mut encoded, last)?;
+                Session::write_raw(self.output, &mut self.stats, &encoded)
+            },
+            None => Session::write_raw(self.output, &mut self.stats, bytes)
+        }
+    }
+
+    fn write_raw(output: &mut W, stats: &mut ConversionStats, bytes: &[u8]) -> UniResult<()> {
+        match output.write_all(bytes) {
+            Err(ioe) => Err(UniError::from_io(code::fsio::OUTPUT, ioe)),
+            Ok(()) => {
+                stats.bytes_out += bytes.len() as u64;
+                Ok(())
+            }
+        }
+    }
+
+    /// Completes the conversion and returns what it did.
+    fn finish(mut self) -> UniResult<ConversionStats> {
+        if let Some(ref mut validator) = self.utf8_validator {
+            validator.finish()?;
+        }
+        if self.pending.is_some() {
+            self.resolve_pending(None, false)?;
+        }
+        self.interrupt_unmatched();
+        for filter in self.regions.iter_mut() {
+            filter.finish();
+        }
+        self.write_through(&[], true)?;
+        // Several pattern indices can stand for the same key (one per marker)
+        for (pati, &count) in self.pattern_matches.iter().enumerate().filter(|&(_, &c)| c > 0) {
+            *self.stats.pattern_matches.entry(self.converter.keys[pati].clone()).or_insert(0) 
+                += count;
+        }
+        Ok(self.stats)
+    }
+}
+
+/// Summary of what a conversion did.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ConversionStats {
+    /// Number of patterns loaded
+    pub patterns: usize,
+    /// Number of inputs converted
+    pub files: usize,
+    /// Number of replacements performed
+    pub matches: u64,
+    /// Bytes read from the input(s)
+    pub bytes_in: u64,
+    /// Bytes written (or, for dry runs, that would have been written) to the output(s)
+    pub bytes_out: u64,
+    /// Number of replacements per key. Keys that were never replaced are missing.
+    pub pattern_matches: BTreeMap<String, u64>
+}
+
+impl ConversionStats {
+    /// Adds the counts of another conversion (e.g., of a different file) to this one.
+    fn add(&mut self, other: &ConversionStats) {
+        self.files += other.files;
+        self.matches += other.matches;
+        self.bytes_in += other.bytes_in;
+        self.bytes_out += other.bytes_out;
+        for (key, count) in &other.pattern_matches {
+            *self.pattern_matches.entry(key.clone()).or_insert(0) += count;
+        }
+    }
+}
+
+/// What the conversion of a single input did, for `--stats-json`.
+#[derive(Debug, Serialize)]
+struct FileStats {
+    path: String,
+    matches: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+    /// Whether anything was replaced or the size changed (e.g., due to different line endings)
+    changed: bool
+}
+
+impl FileStats {
+    fn new(path: String, stats: &ConversionStats) -> FileStats {
+        FileStats {
+            path,
+            matches: stats.matches,
+            bytes_in: stats.bytes_in,
+            bytes_out: stats.bytes_out,
+            changed: stats.matches > 0 || stats.bytes_in != stats.bytes_out
+        }
+    }
+}
+
+/// Summary of a whole run for `--stats-json`.
+#[derive(Debug, Serialize)]
+struct RunStats {
+    patterns: usize,
+    matches: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+    elapsed_ms: i64,
+    files: Vec<FileStats>
+}
+
+/// Collects escape-like tokens (`\` followed by ASCII letters) that were passed through without 
+/// replacement. Tokens can span several chunks of non-matching bytes.
+#[derive(Debug, Default)]
+pub struct UnmatchedTokens {
+    /// Letters of the token currently being scanned, if any
+    pending: Option<Vec<u8>>,
+    counts: BTreeMap<String, u64>
+}
+
+impl UnmatchedTokens {
+    pub fn new() -> UnmatchedTokens {
+        Default::default()
+    }
+
+    /// Number of occurrences for each unmatched token, ordered by token.
+    pub fn counts(&self) -> &BTreeMap<String, u64> {
+        &self.counts
+    }
+
+    /// Adds the counts of another scan (e.g., of a different file) to this one.
+    fn merge(&mut self, other: UnmatchedTokens) {
+        for (token, count) in other.counts {
+            *self.counts.entry(token).or_insert(0) += count;
+        }
+    }
+
+    fn scan(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            if b.is_ascii_alphabetic() {
+                if let Some(ref mut token) = self.pending {
+                    token.push(b);
+                }
+                continue;
+            }
+            self.interrupt();
+            if b == b'\\' {
+                self.pending = Some(Vec::new());
+            }
+        }
+    }
+
+    /// Ends the token currently being scanned.
+    fn interrupt(&mut self) {
+        if let Some(token) = self.pending.take() {
+            if !token.is_empty() {
+                let token = format!("\\{}", String::from_utf8_lossy(&token));
+                *self.counts.entry(token).or_insert(0) += 1;
+            }
+        }
+    }
+}
+
+/// Orders (pattern, replacement, key) triples for the automaton: longer patterns come first, ties 
+/// are broken lexicographically, so the automaton is the same regardless of the iteration order of 
+/// the pattern map.
+///
+/// The automaton cannot be asked for leftmost-longest matches itself: our fork of aho-corasick is 
+/// based on version 0.6, which predates configurable match kinds. Both the streaming and the 
+/// memory-mapped conversion select from its overlapping matches instead, see 
+/// `Converter::leftmost_longest`.
+fn prioritized<I: Iterator<Item=(String, String, String)>>(entries: I) 
+        -> Vec<(String, String, String)> {
+    let mut entries : Vec<(String, String, String)> = entries.collect();
+    entries.sort_by(|a, b| b.0.len().cmp(&a.0.len()).then_with(|| a.0.cmp(&b.0)));
+    // The same text can be reachable through different markers; the first registration wins
+    entries.dedup_by(|a, b| a.0 == b.0);
+    entries
+}
+
+/// Converts `input` to `output` using the escape sequence → replacement mapping in `patterns`.
+pub fn convert_stream<R: Read, W: Write>(input: R, mut output: W, 
+        patterns: &HashMap<String, String>) -> UniResult<()> {
+    let ordered_patterns = patterns.iter().map(|(k,v)| (k.clone(), v.clone())).collect();
+    Converter::new(&ordered_patterns).convert(input, &mut output).map(|_| ())
+}
+
+/// Converts a string in memory using the escape sequence → replacement mapping in `patterns`.
+pub fn convert_str(input: &str, patterns: &HashMap<String, String>) -> String {
+    let mut output = Vec::with_capacity(input.len());
+    convert_stream(input.as_bytes(), &mut output, patterns)
+        .expect("Conversion in memory should not fail.");
+    // Matches and replacements are complete UTF-8 sequences, so the result is valid UTF-8 as well.
+    String::from_utf8(output).expect("Conversion of UTF-8 text should result in UTF-8 text.")
+}
+
+/// Checks the configuration the way a conversion would, without converting anything, and 
+/// reports the result on stdout.
+pub fn validate(config: &Configuration) -> UniResult<()> {
+    check_patterns(config)?;
+    let sources = if config.config_files.is_empty() {
+        "no configuration file".to_owned()
+    } else {
+        config.config_files.iter().map(|path| path.display().to_string())
+            .collect::<Vec<_>>().join(", ")
+    };
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    try_!(writeln!(out, "Configuration is valid: {} patterns from {}", config.patterns.len(), 
+        sources), "<stdout>".to_string(), code::fsio::OUTPUT);
+    Ok(())
+}
+
+/// Checks for problems with the patterns that only show when they are used together.
+fn check_patterns(config: &Configuration) -> UniResult<()> {
+    // With a suffix, a key cannot swallow the beginning of another
+    if !config.reverse && config.markers.iter().any(|marker| marker.suffix.is_empty()) {
+        check_prefixes(&config.patterns, config.strict)?;
+    }
+    Ok(())
+}
+
+/// Runs the conversion described by `config`. See `run_with_stats`. With `--show-config`, the
+/// configuration is printed first (and nothing else happens for a dry run).
 pub fn run(config: &Configuration) -> UniResult<()> {
+    let in_place = config.targets.iter().any(|target| is_in_place(&target.output));
+    if in_place && !config.keep_temp_files {
+        install_interrupt_handler();
+    }
+    if config.show_config {
+        config.show()?;
+        if config.dry_run {
+            return Ok(());
+        }
+    }
+    run_with_stats(config).map(|_| ())
+}
 
-    debug!("Configured input: {:#?}", config.input);
-    debug!("Configured output: {:#?}", config.output);
+/// Exit code of a process interrupted by Ctrl-C (128 + SIGINT), the way shells report it.
+const INTERRUPTED_EXIT_CODE: i32 = 130;
 
+/// Makes sure that an interruption (SIGINT, SIGTERM) doesn't leave the temp files of in-place 
+/// conversions behind.
+fn install_interrupt_handler() {
+    let installed = ::ctrlc::set_handler(|| {
+        Output::remove_temp_files();
+        ::std::process::exit(INTERRUPTED_EXIT_CODE);
+    });
+    if let Err(e) = installed {
+        warn!("Temp files will not be removed if the conversion is interrupted: {}", e);
+    }
+}
+
+/// Prints the patterns of `config` to stdout, sorted by key. Either one `key -> value` line per 
+/// pattern or, with `json`, a single JSON object.
+pub fn list_patterns(config: &Configuration, json: bool) -> UniResult<()> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    if json {
+        try_!(::serde_json::to_writer_pretty(&mut out, &config.patterns)
+            .map_err(io::Error::from), "<stdout>".to_string(), code::fsio::OUTPUT);
+        try_!(writeln!(out), "<stdout>".to_string(), code::fsio::OUTPUT);
+    } else {
+        for (key, value) in &config.patterns {
+            try_!(writeln!(out, "{} -> {}", key, value), "<stdout>".to_string(), 
+                code::fsio::OUTPUT);
+        }
+    }
+    info!("{} patterns", config.patterns.len());
+    Ok(())
+}
+
+/// Runs the conversion described by `config` and returns what it did, totalled over all targets. 
+/// If any target fails, the others are still converted, but the result is the first error.
+pub fn run_with_stats(config: &Configuration) -> UniResult<ConversionStats> {
+    check_patterns(config)?;
     info!("Computing matching automaton ({} patterns)...", config.patterns.len());
     let stopwatch = Stopwatch::start_new();
-    let automaton = AcAutomaton::new(config.patterns.keys().map(|p| format!("\\{}", p)));
-    let lookup_map : Vec<&str> = automaton.patterns().iter().map::<&str,_>(|p| &config.patterns[&p[1 ..]] ).collect();
-    info!("Matching automaton for {} patterns computed in {}ms", config.patterns.len(), stopwatch.elapsed_ms());
+    let converter = if config.reverse {
+        // Replacements are unique (checked when loading the configuration), so each of them 
+        // identifies exactly one key.
+        // Escape sequences are written with the first marker
+        Converter::reverse_with_marker(&config.patterns, &config.markers[0])
+    } else {
+        Converter::with_markers(&config.patterns, &config.markers)
+    };
+    let build_ms = stopwatch.elapsed_ms();
+    info!("Matching automaton for {} patterns computed in {}ms", config.patterns.len(), build_ms);
 
+    if config.buffer_size < converter.longest_pattern() {
+        return Err(error::usage(format!(
+            "The buffer size ({} bytes) must be at least as large as the longest pattern ({} bytes).",
+            config.buffer_size, converter.longest_pattern()))
+            .with_minor(code::usage::INVALID_ARGUMENT));
+    }
+    let converter = converter.with_buffer_size(config.buffer_size)
+        .with_regions(config.regions.clone());
+    let converter = match config.normalization {
+        Some(form) => converter.with_normalization(form),
+        None => converter
+    };
+    let converter = match config.translit {
+        Some(ref translit) => converter.with_transliteration(translit),
+        None => converter
+    };
+    let converter = converter.with_output_format(config.output_format);
+    let converter = converter.with_bom(config.bom).with_line_endings(config.line_endings);
+    let converter = match config.input_encoding {
+        Some(encoding) => converter.with_input_encoding(encoding),
+        None => converter
+    };
+    let converter = match config.output_encoding {
+        Some(encoding) => converter.with_output_encoding(encoding),
+        None => converter
+    };
+    // Match modes and trailing spaces concern the escape sequences, so they don't apply in reverse
+    let converter = if config.reverse { 
+        converter 
+    } else { 
+        converter.with_match_mode(config.match_mode)
+            .with_trailing_space_consumed(config.consume_trailing_space)
+    };
+    let converter = converter.with_trace(config.trace).with_utf8_check(config.check_utf8)
+        .with_confirmation(config.interactive).with_color(config.color)
+        .with_max_replacements(config.max_replacements);
+    let converter = match config.match_log {
+        Some(ref destination) => converter.with_match_log(open_match_log(destination)?),
+        None => converter
+    };
 
-    let mut output = config.output.open()?;
-    {
-        // Region where the input file is open
-        let input = config.input.open()?;
-        let mut chunks = StreamChunks::with_capacity(&automaton, input, 512);
-        chunks.all::<_, UniError>(|chunk| {
-            let out_bytes = match chunk {
-                StreamChunk::Matching(m) => {
-                    // TODO: skip text-based lookup in favour of pattern index.
-                    let replacement = lookup_map[m.pati];
-                    debug!("Found {} replacing it with {}", automaton.pattern(m.pati), replacement);
-                    replacement.as_bytes()
+    let mut unmatched = if config.report_unmatched { Some(UnmatchedTokens::new()) } else { None };
+    let mut stats = ConversionStats { patterns: config.patterns.len(), .. Default::default() };
+    let mut file_stats = Vec::new();
+    let match_stopwatch = Stopwatch::start_new();
+    let mut failures = if config.jobs > 1 && config.targets.len() > 1 {
+        run_parallel(config, &converter, &mut stats, &mut file_stats, unmatched.as_mut())
+    } else {
+        let mut failures = Vec::new();
+        for target in &config.targets {
+            if converter.quit_requested() {
+                break;
+            }
+            match run_target(config, &converter, target, unmatched.as_mut()) {
+                Ok(target_stats) => {
+                    stats.add(&target_stats);
+                    file_stats.push(FileStats::new(target.input.name(), &target_stats));
                 },
-                StreamChunk::NonMatching(bs) => {
-                    debug!("Forwarding {} non-matching bytes.", bs.len());
-                    bs
+                Err(e) => failures.push((target.input.name(), e))
+            }
+        }
+        failures
+    };
+    let match_ms = match_stopwatch.elapsed_ms();
+    if let Err(e) = converter.flush_match_log() {
+        failures.push((match_log_name(config), UniError::from_io(code::fsio::OUTPUT, e)));
+    }
+    if converter.replacement_limit_reached() {
+        info!("Stopped replacing after {} replacements (--max-replacements)", stats.matches);
+    }
+
+    if let Some(ref u) = unmatched {
+        report_unmatched(u);
+    }
+    for (key, count) in &stats.pattern_matches {
+        debug!("{} replacements of {}", count, key);
+    }
+    if config.stats {
+        report_stats(&stats, stopwatch.elapsed_ms());
+    }
+    if config.measure {
+        report_measurement(build_ms, match_ms, stats.bytes_in);
+    }
+    if let Some(ref report_path) = config.report {
+        write_report(report_path, config, &stats)?;
+    }
+    if let Some(ref destination) = config.stats_json {
+        write_stats_json(destination, &stats, file_stats, stopwatch.elapsed_ms())?;
+    }
+    let result = summarize_failures(failures).map(|()| stats);
+    if config.watch {
+        // A failure of the initial conversion might be what the user is about to fix
+        if let Err(ref e) = result {
+            error!("{}", e);
+        }
+        watch(config, &converter);
+    }
+    result
+}
+
+/// How often `--watch` looks for changed inputs.
+const WATCH_INTERVAL_MS: u64 = 500;
+
+/// Converts each target again whenever its input changes (`--watch`). The converter is reused, 
+/// so the automaton is only built once. Runs until the process is interrupted.
+fn watch(config: &Configuration, converter: &Converter) -> ! {
+    let mut modified : Vec<_> = config.targets.iter()
+        .map(|target| modification_time(&target.input)).collect();
+    info!("Watching {} files for changes", config.targets.len());
+    loop {
+        thread::sleep(Duration::from_millis(WATCH_INTERVAL_MS));
+        for (target, last_modified) in config.targets.iter().zip(modified.iter_mut()) {
+            let current = modification_time(&target.input);
+            if current == *last_modified {
+                continue;
+            }
+            let name = target.input.name();
+            info!("{} changed, converting it again", name);
+            match run_target(config, converter, target, None) {
+                Ok(stats) => info!("{} replacements in {}", stats.matches, name),
+                Err(e) => error!("Conversion of {} failed: {}", name, e)
+            }
+            // An in-place conversion replaces the input; that is no reason to convert it again
+            *last_modified = modification_time(&target.input);
+        }
+    }
+}
+
+fn modification_time(input: &Input) -> Option<SystemTime> {
+    match *input {
+        Input::File(ref path) => fs::metadata(path).and_then(|stat| stat.modified()).ok(),
+        Input::Stdin(_) => None
+    }
+}
+
+/// Converts the targets on `config.jobs` worker threads. The converter is shared by all workers; 
+/// every worker streams its targets independently. Failures are returned in target order.
+fn run_parallel(config: &Configuration, converter: &Converter, stats: &mut ConversionStats,
+        file_stats: &mut Vec<FileStats>, unmatched: Option<&mut UnmatchedTokens>) 
+        -> Vec<(String, UniError)> {
+    let next_target = AtomicUsize::new(0);
+    let failures = Mutex::new(Vec::new());
+    let files = Mutex::new(Vec::new());
+    let stats = Mutex::new(stats);
+    let unmatched = Mutex::new(unmatched);
+    let workers = min(config.jobs, config.targets.len());
+    info!("Converting {} files on {} threads", config.targets.len(), workers);
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                let mut local_stats = ConversionStats::default();
+                let mut local_unmatched = 
+                    if config.report_unmatched { Some(UnmatchedTokens::new()) } else { None };
+                loop {
+                    let index = next_target.fetch_add(1, Ordering::SeqCst);
+                    let target = match config.targets.get(index) {
+                        Some(target) => target,
+                        None => break
+                    };
+                    match run_target(config, converter, target, local_unmatched.as_mut()) {
+                        Ok(target_stats) => {
+                            local_stats.add(&target_stats);
+                            files.lock().unwrap().push(
+                                (index, FileStats::new(target.input.name(), &target_stats)));
+                        },
+                        Err(e) => failures.lock().unwrap().push((index, target.input.name(), e))
+                    }
+                }
+                stats.lock().unwrap().add(&local_stats);
+                if let (Some(local), Some(total)) = 
+                        (local_unmatched, unmatched.lock().unwrap().as_mut()) {
+                    total.merge(local);
                 }
+            });
+        }
+    });
+
+    let mut files = files.into_inner().unwrap();
+    files.sort_by_key(|&(index, _)| index);
+    file_stats.extend(files.into_iter().map(|(_, file)| file));
+
+    let mut failures = failures.into_inner().unwrap();
+    failures.sort_by_key(|&(index, _, _)| index);
+    failures.into_iter().map(|(_, name, e)| (name, e)).collect()
+}
+
+/// Combines the failures of individual targets into the result of the whole run. The first 
+/// failure becomes the result (and determines the exit code), all others are logged.
+fn summarize_failures(failures: Vec<(String, UniError)>) -> UniResult<()> {
+    if failures.len() > 1 && failures.iter().all(|&(_, ref e)| e.is_check_failure()) {
+        // The file names have already been printed, one per line
+        let names = failures.into_iter().map(|(name, _)| name).collect();
+        return Err(UniError::new(0, UniErrorData::CheckFailed(names)));
+    }
+
+    let mut failures = failures.into_iter();
+    match failures.next() {
+        None => Ok(()),
+        Some((_, first)) => {
+            for (name, e) in failures {
+                error!("Conversion of {} failed: {}", name, e);
+            }
+            Err(first)
+        }
+    }
+}
+
+/// Converts a single target according to the configured mode.
+fn run_target(config: &Configuration, converter: &Converter, target: &Target, 
+        unmatched: Option<&mut UnmatchedTokens>) -> UniResult<ConversionStats> {
+    debug!("Configured input: {:#?}", target.input);
+    debug!("Configured output: {:#?}", target.output);
+
+    if config.check {
+        return check(target, converter);
+    }
+    if config.diff {
+        return ignore_broken_pipe(diff(target, converter));
+    }
+
+    if config.dry_run {
+        let stats = convert_input(config, converter, target, &mut io::sink(), unmatched)?;
+        info!("Dry run: {} replacements in {}", stats.matches, target.input.name());
+        Ok(stats)
+    } else {
+        let mut output = target.output.open()?;
+        let result = {
+            // Region where the input file is open. The output is buffered because matches 
+            // result in lots of small writes. All bytes need to land before the output is closed.
+            let mut buffered = BufWriter::new(&mut output);
+            let converted = if target.gzip {
+                // Finished explicitly; dropping the encoder would swallow errors
+                let mut encoder = GzEncoder::new(&mut buffered, Compression::default());
+                convert_input(config, converter, target, &mut encoder, unmatched)
+                    .and_then(|stats| match encoder.finish() {
+                        Err(ioe) => Err(UniError::new(code::fsio::OUTPUT, UniErrorData::Io(ioe))),
+                        Ok(_) => Ok(stats)
+                    })
+            } else {
+                convert_input(config, converter, target, &mut buffered, unmatched)
             };
-            match output.write_all(out_bytes) {
+            converted.and_then(|stats| match buffered.flush() {
                 Err(ioe) => Err(UniError::new(code::fsio::OUTPUT, UniErrorData::Io(ioe))),
-                Ok(()) => Ok(())
+                Ok(()) => Ok(stats)
+            })
+        };
+
+        // Return the output writer; behaviour depends on what the user asked for and whether the 
+        // conversion succeeded
+        match result {
+            // Swapping in an identical copy would only produce a needless backup and a new 
+            // modification time
+            Ok(stats) if is_in_place(&target.output) && stats.matches == 0 
+                    && !converter.rewrites_unmatched_text() => {
+                info!("{} does not need conversion, leaving it alone", target.input.name());
+                target.output.abort(output);
+                Ok(stats)
+            },
+            Ok(stats) => {
+                target.output.close(output)?;
+                Ok(stats)
+            },
+            Err(e) => {
+                target.output.abort(output);
+                ignore_broken_pipe(Err(e))
             }
-        })?;
+        }
+    }
+}
+
+fn is_in_place(output: &Output) -> bool {
+    match *output {
+        Output::InPlace(..) => true,
+        _ => false
+    }
+}
+
+/// A reader that stops reading the output early (e.g., `to-uni --stdout in.tex | head`) is not a 
+/// failure; there is just nobody left to write to.
+fn ignore_broken_pipe(result: UniResult<ConversionStats>) -> UniResult<ConversionStats> {
+    match result {
+        Err(ref e) if e.is_broken_pipe() => {
+            info!("The output was closed by its reader, stopping early");
+            Ok(ConversionStats::default())
+        },
+        other => other
+    }
+}
+
+fn convert_input<W: Write>(config: &Configuration, converter: &Converter, target: &Target, 
+        output: &mut W, unmatched: Option<&mut UnmatchedTokens>) -> UniResult<ConversionStats> {
+    match target.input {
+        // Compressed input has to be streamed through the decoder
+        Input::File(ref path) if config.mmap && !target.gzip => 
+            converter.process_mapped(path, output, &target.input.name(), unmatched),
+        _ => {
+            // The progress would get mixed up with the converted text on a terminal
+            let progress = config.progress && !is_stdout(&target.output);
+            converter.process(open_input(target, progress)?, output, &target.input.name(), 
+                unmatched)
+        }
     }
+}
 
-    // Return the output writer; behaviour depends on what the user asked for
-    config.output.close(output)
+fn is_stdout(output: &Output) -> bool {
+    match *output {
+        Output::Stdout => true,
+        _ => false
+    }
 }
 
-// This automatic conversion affects the input stream. Output IO errors are handled explicitly.
-impl From<StreamChunkError<UniError>> for UniError {
-    fn from(e: StreamChunkError<UniError>) -> UniError {
-        match e {
-            StreamChunkError::User(ue) => ue,
-            StreamChunkError::Io(ioe) => UniError::new(code::fsio::INPUT, UniErrorData::Io(ioe))
+/// Opens the input of `target`, decompressing it if necessary. With `progress`, the bytes read 
+/// (before decompression) are reported to standard error.
+fn open_input(target: &Target, progress: bool) -> UniResult<Box<Read>> {
+    let input = target.input.open()?;
+    let input : Box<Read> = if progress {
+        let total = match target.input {
+            Input::File(ref path) => fs::metadata(path).ok().map(|metadata| metadata.len()),
+            Input::Stdin(_) => None
+        };
+        Box::new(ProgressReader::new(input, target.input.name(), total))
+    } else {
+        input
+    };
+    if target.gzip {
+        Ok(Box::new(GzDecoder::new(input)))
+    } else {
+        Ok(input)
+    }
+}
+
+/// Warns about keys that are a prefix of another key (e.g., `alpha` and `alphabet`). Where both 
+/// match, the longer one wins, which can be surprising. With `strict`, that is an error.
+fn check_prefixes(patterns: &BTreeMap<String, String>, strict: bool) -> UniResult<()> {
+    let mut overlaps = Vec::new();
+    // In sorted order, the keys that are a prefix of the current key are among the keys that were 
+    // a prefix of the previous one (or that key itself)
+    let mut prefixes : Vec<&str> = Vec::new();
+    for key in patterns.keys() {
+        while prefixes.last().map_or(false, |prefix| !key.starts_with(prefix)) {
+            prefixes.pop();
+        }
+        for prefix in &prefixes {
+            warn!("Key {} is a prefix of key {}; where both match, {} wins.", prefix, key, key);
+            overlaps.push(format!("{}/{}", prefix, key));
         }
+        prefixes.push(key);
+    }
+    if strict && !overlaps.is_empty() {
+        return Err(error::usage(format!("Some keys are a prefix of another key: {}", 
+            overlaps.join(", "))).with_minor(code::usage::INVALID_CONFIG_FILE));
+    }
+    Ok(())
+}
+
+/// Writes the number of replacements of every pattern (including the unused ones) to 
+/// `report_path` as a JSON object.
+fn write_report(report_path: &Path, config: &Configuration, stats: &ConversionStats) 
+        -> UniResult<()> {
+    let counts : BTreeMap<&str, u64> = config.patterns.keys()
+        .map(|key| (key.as_str(), stats.pattern_matches.get(key).cloned().unwrap_or(0)))
+        .collect();
+    let name = report_path.to_string_lossy().into_owned();
+    let mut file = try_!(File::create(report_path), name.clone(), code::fsio::OUTPUT);
+    try_!(::serde_json::to_writer_pretty(&mut file, &counts).map_err(io::Error::from), 
+        name.clone(), code::fsio::OUTPUT);
+    try_!(writeln!(file), name, code::fsio::OUTPUT);
+    info!("Wrote the number of replacements per pattern to {}", report_path.display());
+    Ok(())
+}
+
+/// Opens the destination of --print0-report.
+fn open_match_log(destination: &MatchLog) -> UniResult<Box<Write + Send>> {
+    match *destination {
+        MatchLog::File(ref path) => Ok(Box::new(try_!(File::create(path), 
+            path.to_string_lossy().into_owned(), code::fsio::OUTPUT))),
+        MatchLog::Fd(fd) => open_fd(fd)
+    }
+}
+
+/// Takes over the file descriptor `fd`, which the caller opened for this process (e.g., with 
+/// `3>records` in the shell).
+#[cfg(unix)]
+fn open_fd(fd: i32) -> UniResult<Box<Write + Send>> {
+    use std::os::unix::io::FromRawFd;
+    if fd < 0 {
+        return Err(error::usage(format!("Invalid file descriptor: {}", fd)));
+    }
+    // The descriptor was opened for this process by its caller and is not used otherwise
+    Ok(Box::new(unsafe { File::from_raw_fd(fd) }))
+}
+
+#[cfg(not(unix))]
+fn open_fd(_fd: i32) -> UniResult<Box<Write + Send>> {
+    Err(error::usage(
+        "--report-fd is not supported on this platform. Use --print0-report=FILE.".to_owned()))
+}
+
+fn match_log_name(config: &Configuration) -> String {
+    match config.match_log {
+        Some(MatchLog::File(ref path)) => path.to_string_lossy().into_owned(),
+        Some(MatchLog::Fd(fd)) => format!("<fd {}>", fd),
+        None => String::new()
+    }
+}
+
+/// Writes the summary of the run as a single JSON object to `destination`.
+fn write_stats_json(destination: &Output, stats: &ConversionStats, files: Vec<FileStats>, 
+        elapsed_ms: i64) -> UniResult<()> {
+    let run_stats = RunStats {
+        patterns: stats.patterns,
+        matches: stats.matches,
+        bytes_in: stats.bytes_in,
+        bytes_out: stats.bytes_out,
+        elapsed_ms,
+        files
+    };
+    let name = match *destination {
+        Output::OtherFile(ref path, _) => path.to_string_lossy().into_owned(),
+        _ => "<stdout>".to_owned()
+    };
+    let mut out = destination.open()?;
+    try_!(::serde_json::to_writer(&mut out, &run_stats).map_err(io::Error::from)
+        .and_then(|()| writeln!(out)), name, code::fsio::OUTPUT);
+    destination.close(out)
+}
+
+fn report_stats(stats: &ConversionStats, elapsed_ms: i64) {
+    let _ = writeln!(&mut io::stderr(), 
+        "{} patterns, {} files, {} replacements, {} bytes read, {} bytes written, {}ms", 
+        stats.patterns, stats.files, stats.matches, stats.bytes_in, stats.bytes_out, elapsed_ms);
+}
+
+/// Prints how long building the automaton and converting all inputs took. The throughput refers to 
+/// the bytes read.
+fn report_measurement(build_ms: i64, match_ms: i64, bytes_in: u64) {
+    // Less than a millisecond is rounded up rather than dividing by zero
+    let megabytes_per_second = bytes_in as f64 / 1e6 / (max(match_ms, 1) as f64 / 1e3);
+    let _ = writeln!(&mut io::stderr(), "build: {}ms, match: {}ms, throughput: {:.1} MB/s", 
+        build_ms, match_ms, megabytes_per_second);
+}
+
+fn report_unmatched(unmatched: &UnmatchedTokens) {
+    let stderr = io::stderr();
+    let mut err = stderr.lock();
+    for (token, count) in unmatched.counts() {
+        let _ = writeln!(err, "Unmatched {}: {}", token, count);
+    }
+}
+
+/// Runs the conversion without writing output and fails if any replacements would be performed.
+fn check(target: &Target, converter: &Converter) -> UniResult<ConversionStats> {
+    let name = target.input.name();
+    let stats = converter.process(open_input(target, false)?, &mut io::sink(), &name, None)?;
+    if stats.matches > 0 {
+        info!("{} needs conversion ({} replacements)", name, stats.matches);
+        // Make sure the developer gets to know what to fix, independent of the log level
+        let _ = writeln!(&mut io::stderr(), "{}", name);
+        Err(UniError::new(0, UniErrorData::CheckFailed(vec![name])))
+    } else {
+        info!("{} does not need conversion", name);
+        Ok(stats)
+    }
+}
+
+/// Prints the changes a conversion would make as a unified diff to stdout.
+fn diff(target: &Target, converter: &Converter) -> UniResult<ConversionStats> {
+    let name = target.input.name();
+    let mut original = Vec::new();
+    {
+        let mut input = open_input(target, false)?;
+        try_!(input.read_to_end(&mut original), name.clone(), code::fsio::INPUT);
+    }
+    let mut converted = Vec::with_capacity(original.len());
+    let stats = converter.process(&original[..], &mut converted, &name, None)?;
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let changed = try_!(::diff::unified_diff(&mut out, &format!("a/{}", name), 
+        &format!("b/{}", name), &original, &converted), "<stdout>".to_string(), code::fsio::OUTPUT);
+    if !changed {
+        info!("{} does not need conversion", name);
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config;
+    use testing::{self, TempDir};
+
+    fn patterns(entries: &[(&str, &str)]) -> BTreeMap<String, String> {
+        entries.iter().map(|&(k, v)| (k.to_owned(), v.to_owned())).collect()
+    }
+
+    fn convert(converter: &Converter, input: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+        converter.convert(input, &mut output).expect("Conversion in memory should not fail.");
+        output
+    }
+
+    /// Converts `input` in chunks of the default buffer size and checks that the result is valid 
+    /// UTF-8 and identical to a conversion in a single chunk.
+    fn convert_streamed(table: &BTreeMap<String, String>, input: &str) -> String {
+        let streamed = convert(&Converter::new(table), input.as_bytes());
+        let single = convert(&Converter::new(table).with_buffer_size(input.len()), 
+            input.as_bytes());
+        assert_eq!(streamed, single);
+        String::from_utf8(streamed).expect("The output should be valid UTF-8.")
+    }
+
+    #[test]
+    fn replacement_straddling_the_chunk_boundary() {
+        let table = patterns(&[("alpha", "α")]);
+        for offset in DEFAULT_BUFFER_SIZE - 8 .. DEFAULT_BUFFER_SIZE + 2 {
+            let padding = "x".repeat(offset);
+            let input = format!("{}\\alpha and ü", padding);
+            assert_eq!(convert_streamed(&table, &input), format!("{}α and ü", padding), 
+                "pattern at byte {}", offset);
+        }
+    }
+
+    #[test]
+    fn multibyte_text_split_by_the_chunk_boundary() {
+        let table = patterns(&[("alpha", "α")]);
+        for offset in DEFAULT_BUFFER_SIZE - 4 .. DEFAULT_BUFFER_SIZE + 1 {
+            // A four-byte character that no pattern matches, directly followed by a replacement
+            let padding = "x".repeat(offset);
+            let input = format!("{}😀\\alpha€", padding);
+            assert_eq!(convert_streamed(&table, &input), format!("{}😀α€", padding), 
+                "character at byte {}", offset);
+        }
+    }
+
+    #[test]
+    fn escaped_backslash_is_not_the_start_of_a_command() {
+        let converter = Converter::new(&patterns(&[("alpha", "α")]));
+        assert_eq!(convert(&converter, b"\\\\alpha"), b"\\\\alpha");
+        assert_eq!(convert(&converter, b"\\\\\\alpha"), "\\\\α".as_bytes());
+        assert_eq!(convert(&converter, b"a\\\\"), b"a\\\\");
+        assert_eq!(convert(&converter, b"\\alpha\\\\ \\alpha"), "α\\\\ α".as_bytes());
+    }
+
+    #[test]
+    fn buffer_is_never_smaller_than_the_longest_pattern() {
+        let table = patterns(&[("alpha", "α")]);
+        assert_eq!(Converter::new(&table).longest_pattern(), "\\alpha".len());
+        for buffer_size in 1 .. "\\alpha".len() + 2 {
+            let converter = Converter::new(&table).with_buffer_size(buffer_size);
+            assert_eq!(convert(&converter, b"x\\alpha \\alpha"), "xα α".as_bytes(),
+                "buffer of {} bytes", buffer_size);
+        }
+    }
+
+    #[test]
+    fn directives_toggle_the_conversion() {
+        let regions = RegionSettings { 
+            directive_marker: ::regions::DEFAULT_DIRECTIVE_MARKER.to_owned(), 
+            .. RegionSettings::default() 
+        };
+        let converter = Converter::new(&patterns(&[("alpha", "α")])).with_regions(regions);
+        let input = concat!("\\alpha\n% to-uni:off\n\\alpha\n% to-uni:on\n\\alpha\n",
+            "% to-uni:ignore-next-line\n\\alpha\n\\alpha\n");
+        let expected = concat!("α\n% to-uni:off\n\\alpha\n% to-uni:on\nα\n",
+            "% to-uni:ignore-next-line\n\\alpha\nα\n");
+        assert_eq!(String::from_utf8(convert(&converter, input.as_bytes())).unwrap(), expected);
+    }
+
+    #[test]
+    fn escape_formats_only_apply_to_replacements() {
+        let table = patterns(&[("alpha", "α"), ("mathcal{A}", "𝒜")]);
+        let input = "é \\alpha \\mathcal{A} 😀".as_bytes();
+        let rust = Converter::new(&table).with_output_format(OutputFormat::RustEscape);
+        assert_eq!(String::from_utf8(convert(&rust, input)).unwrap(), 
+            "é \\u{3B1} \\u{1D49C} 😀");
+        let json = Converter::new(&table).with_output_format(OutputFormat::JsonEscape);
+        assert_eq!(String::from_utf8(convert(&json, input)).unwrap(), 
+            "é \\u03b1 \\ud835\\udc9c 😀");
+    }
+
+    #[test]
+    fn line_endings_are_preserved_or_rewritten() {
+        let table = patterns(&[("alpha", "α")]);
+        let input = b"\\alpha\r\nmixed\nlone\rend\r\n";
+        assert_eq!(convert(&Converter::new(&table), input), 
+            "α\r\nmixed\nlone\rend\r\n".as_bytes());
+        // With a tiny buffer, some `\r\n` are split between two chunks
+        for buffer_size in &[DEFAULT_BUFFER_SIZE, 2] {
+            let lf = Converter::new(&table).with_buffer_size(*buffer_size)
+                .with_line_endings(LineEndings::Lf);
+            assert_eq!(convert(&lf, input), "α\nmixed\nlone\rend\n".as_bytes());
+            let crlf = Converter::new(&table).with_buffer_size(*buffer_size)
+                .with_line_endings(LineEndings::Crlf);
+            assert_eq!(convert(&crlf, input), "α\r\nmixed\r\nlone\rend\r\n".as_bytes());
+        }
+    }
+
+    /// Accepts a few bytes, then fails like a pipe whose reader has gone away.
+    struct ClosedPipe {
+        capacity: usize
+    }
+
+    impl Write for ClosedPipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.capacity == 0 {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "reader closed"));
+            }
+            let written = buf.len().min(self.capacity);
+            self.capacity -= written;
+            Ok(written)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn closed_output_pipe_ends_the_conversion_successfully() {
+        let converter = Converter::new(&patterns(&[("alpha", "α")]));
+        let input = "\\alpha and more\n".repeat(1000);
+        let err = converter.process(input.as_bytes(), &mut ClosedPipe { capacity: 100 }, 
+            "doc.tex", None).unwrap_err();
+        assert!(err.is_broken_pipe(), "{}", err);
+        assert_eq!(ignore_broken_pipe(Err(err)).unwrap(), ConversionStats::default());
+
+        let other = io::Error::new(io::ErrorKind::Other, "disk full");
+        assert!(ignore_broken_pipe(Err(other.into())).is_err());
+    }
+
+    #[test]
+    fn gzipped_file_is_converted_in_place() {
+        let dir = TempDir::new();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"\\alpha and \\alpha").unwrap();
+        let compressed = encoder.finish().unwrap();
+        let path = dir.write("doc.tex.gz", &compressed);
+        let config = Configuration::from_args(testing::args(&["--no-config", 
+            "--pattern=alpha=α", "--in-place", path.to_str().unwrap()])).unwrap();
+        assert_eq!(run_with_stats(&config).unwrap().matches, 2);
+
+        let mut converted = String::new();
+        GzDecoder::new(File::open(&path).unwrap()).read_to_string(&mut converted).unwrap();
+        assert_eq!(converted, "α and α");
+        assert_eq!(fs::read(dir.path().join("doc.tex.gz.bak")).unwrap(), compressed);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symbolic_link_is_kept_and_its_target_converted() {
+        let dir = TempDir::new();
+        let target = dir.write("real.tex", b"\\alpha");
+        let link = dir.path().join("link.tex");
+        ::std::os::unix::fs::symlink("real.tex", &link).unwrap();
+        let config = Configuration::from_args(testing::args(&["--no-config", 
+            "--pattern=alpha=α", "--in-place", link.to_str().unwrap()])).unwrap();
+        run_with_stats(&config).unwrap();
+        assert_eq!(fs::read_link(&link).unwrap(), Path::new("real.tex"));
+        assert_eq!(fs::read(&target).unwrap(), "α".as_bytes());
+        assert_eq!(fs::read(dir.path().join("real.tex.bak")).unwrap(), b"\\alpha");
+
+        let err = Configuration::from_args(testing::args(&["--no-config", "--pattern=alpha=α", 
+            "--no-follow-symlinks", "--in-place", link.to_str().unwrap()])).err().unwrap();
+        assert_eq!((err.code_major(), err.code_minor()), (0, code::usage::INPUT_NOT_A_FILE));
+    }
+
+    #[test]
+    fn failed_in_place_conversion_leaves_no_temp_file() {
+        let dir = TempDir::new();
+        let mut original = b"\\alpha ".repeat(64);
+        original.extend_from_slice(b"\xff\\alpha");
+        let path = dir.write("doc.tex", &original);
+        let config = Configuration::from_args(testing::args(&["--no-config",
+            "--pattern=alpha=α", "--check-utf8", "--buffer-size=16", "--in-place",
+            path.to_str().unwrap()])).unwrap();
+        let err = run_with_stats(&config).err().unwrap();
+        assert_eq!(err.error_code(), 63);
+        assert_eq!(fs::read(&path).unwrap(), original);
+        let leftovers : Vec<_> = fs::read_dir(dir.path()).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name.starts_with(".~") && name.ends_with(".tmp"))
+            .collect();
+        assert!(leftovers.is_empty(), "{:?}", leftovers);
+    }
+
+    #[test]
+    fn match_log_records_start_with_the_input_name() {
+        let dir = TempDir::new();
+        let log_path = dir.path().join("records");
+        let converter = Converter::new(&patterns(&[("alpha", "α"), ("beta", "β")]))
+            .with_match_log(Box::new(File::create(&log_path).unwrap()));
+        let mut output = Vec::new();
+        converter.process(&b"\\alpha or \\beta"[..], &mut output, "doc.tex", None).unwrap();
+        converter.process(&b"\\beta"[..], &mut output, "other.tex", None).unwrap();
+        converter.flush_match_log().unwrap();
+        let fields = ["doc.tex", "0", "\\alpha", "α", "doc.tex", "10", "\\beta", "β", 
+            "other.tex", "0", "\\beta", "β"];
+        let expected : String = fields.iter().map(|field| format!("{}\0", field)).collect();
+        assert_eq!(String::from_utf8(fs::read(&log_path).unwrap()).unwrap(), expected);
+    }
+
+    #[test]
+    fn config_template_passes_the_strict_checks() {
+        let dir = TempDir::new();
+        let path = dir.path().join("to-uni.yml");
+        config::write_config_template(&path, false).unwrap();
+        let config = Configuration::from_args(testing::args(
+            &["--config", path.to_str().unwrap(), "--strict", "-", "--stdout"])).unwrap();
+        check_patterns(&config).unwrap();
+        assert_eq!(config.patterns.get("rightarrow").map(|v| v.as_str()), Some("→"));
+    }
+
+    #[test]
+    fn prioritized_puts_longer_patterns_first_regardless_of_order() {
+        let entry = |pattern: &str| (pattern.to_owned(), String::new(), String::new());
+        let forward = prioritized(vec![entry("\\alpha"), entry("\\alphabeta")].into_iter());
+        let backward = prioritized(vec![entry("\\alphabeta"), entry("\\alpha")].into_iter());
+        assert_eq!(forward, backward);
+        assert_eq!(forward[0].0, "\\alphabeta");
+    }
+
+    #[test]
+    fn leftmost_longest_prefers_the_longer_of_two_overlapping_patterns() {
+        let converter = Converter::new(&patterns(&[("alpha", "α"), ("alphabeta", "αβ")]));
+        let selected : Vec<(&str, usize, usize)> = converter
+            .leftmost_longest(b"\\alphabeta \\alpha").into_iter()
+            .map(|(pati, start, end)| (&converter.keys[pati][..], start, end))
+            .collect();
+        assert_eq!(selected, vec![("alphabeta", 0, 10), ("alpha", 11, 17)]);
+    }
+
+    #[test]
+    fn streaming_prefers_the_longer_of_two_overlapping_patterns() {
+        let table = patterns(&[("alpha", "α"), ("alphabeta", "αβ")]);
+        assert_eq!(convert(&Converter::new(&table), b"\\alphabeta"), "αβ".as_bytes());
+        assert_eq!(convert(&Converter::new(&table), b"\\alphabet"), "αbet".as_bytes());
+        let input = "\\alphabeta, \\alpha, \\alphabet \\alphabeta";
+        for buffer_size in 1 .. input.len() + 1 {
+            let converter = Converter::new(&table).with_buffer_size(buffer_size);
+            assert_eq!(String::from_utf8(convert(&converter, input.as_bytes())).unwrap(), 
+                "αβ, α, αbet αβ", "buffer of {} bytes", buffer_size);
+        }
+    }
+
+    #[test]
+    fn longest_of_the_patterns_ending_at_the_same_position_wins() {
+        let arrows = patterns(&[("->", "→"), ("-->", "⟶")]);
+        let converter = Converter::with_markers(&arrows, &[Marker::new("", "")]);
+        assert_eq!(convert(&converter, b"a -> b --> c ---> d"), "a → b ⟶ c -⟶ d".as_bytes());
+        // The memory-mapped conversion selects the same matches
+        let haystack = b"a -> b --> c ---> d";
+        let selected : Vec<(usize, usize)> = converter.leftmost_longest(haystack).iter()
+            .map(|&(_, start, end)| (start, end)).collect();
+        assert_eq!(selected, vec![(2, 4), (7, 10), (14, 17)]);
+    }
+
+    #[test]
+    fn memory_mapped_and_streaming_output_is_identical() {
+        let dir = TempDir::new();
+        let table = patterns(&[("alpha", "α"), ("alphabeta", "αβ"), ("beta", "β"), 
+            ("to", "→"), ("top", "⊤")]);
+        let text = "\\alphabeta \\alphabet\\beta \\top\\to\\\\alpha \\topalpha\n";
+        let path = dir.write("doc.tex", text.repeat(100).as_bytes());
+        for buffer_size in &[1, 7, 64, DEFAULT_BUFFER_SIZE] {
+            let converter = Converter::new(&table).with_buffer_size(*buffer_size);
+            let mut streamed = Vec::new();
+            converter.process(File::open(&path).unwrap(), &mut streamed, "doc.tex", None).unwrap();
+            let mut mapped = Vec::new();
+            converter.process_mapped(&path, &mut mapped, "doc.tex", None).unwrap();
+            assert_eq!(String::from_utf8(streamed).unwrap(), String::from_utf8(mapped).unwrap(),
+                "buffer of {} bytes", buffer_size);
+        }
+    }
+
+    /// Compares the throughput of the memory-mapped and the streaming conversion (--mmap). Run 
+    /// it in release mode: `cargo test --release -- --ignored --nocapture mmap_vs_streaming`.
+    #[test]
+    #[ignore]
+    fn mmap_vs_streaming() {
+        let dir = TempDir::new();
+        let line = "Let \\alpha and \\beta be angles, \\gamma = \\pi - \\alpha - \\beta.\n";
+        let path = dir.write("large.tex", line.repeat(64 * 1024 * 1024 / line.len()).as_bytes());
+        let size_mb = fs::metadata(&path).unwrap().len() as f64 / (1024.0 * 1024.0);
+        let converter = Converter::new(&patterns(&[("alpha", "α"), ("beta", "β"), 
+            ("gamma", "γ"), ("pi", "π")]));
+
+        let stopwatch = Stopwatch::start_new();
+        let streamed = converter.process(File::open(&path).unwrap(), &mut io::sink(), "large.tex", 
+            None).unwrap();
+        let streamed_ms = stopwatch.elapsed_ms();
+        let stopwatch = Stopwatch::start_new();
+        let mapped = converter.process_mapped(&path, &mut io::sink(), "large.tex", None).unwrap();
+        let mapped_ms = stopwatch.elapsed_ms();
+
+        assert_eq!(streamed.matches, mapped.matches);
+        println!("{:.0} MB: streaming {} ms ({:.0} MB/s), memory-mapped {} ms ({:.0} MB/s)", 
+            size_mb, streamed_ms, size_mb * 1000.0 / max(streamed_ms, 1) as f64, mapped_ms, 
+            size_mb * 1000.0 / max(mapped_ms, 1) as f64);
     }
 }
@@ -0,0 +1,274 @@
+//! Line-based unified diffs, used to preview a conversion without applying it.
+
+use std::cmp::min;
+use std::io::{self, Write};
+
+/// Number of unchanged lines shown around each change.
+const CONTEXT: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Edit {
+    /// Line is present in both texts (index into old text, index into new text)
+    Keep(usize, usize),
+    /// Line of the old text is missing from the new text
+    Delete(usize),
+    /// Line of the new text is missing from the old text
+    Insert(usize)
+}
+
+/// Splits text into lines, keeping the line terminators. The last line might not have one.
+fn split_lines(text: &[u8]) -> Vec<&[u8]> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for (i, &b) in text.iter().enumerate() {
+        if b == b'\n' {
+            lines.push(&text[start .. i + 1]);
+            start = i + 1;
+        }
+    }
+    if start < text.len() {
+        lines.push(&text[start ..]);
+    }
+    lines
+}
+
+/// Computes a shortest edit script using the linear space variant of Myers' algorithm. Runs in 
+/// O((N+M)D), which is cheap for conversions because they typically touch few lines, and needs 
+/// O(N+M) memory even for texts that have nothing in common.
+fn shortest_edit(old: &[&[u8]], new: &[&[u8]]) -> Vec<Edit> {
+    let mut edits = Vec::with_capacity(old.len() + new.len());
+    diff_range(old, new, 0, 0, &mut edits);
+    edits
+}
+
+/// Appends the edits turning `old` into `new` to `edits`. The slices start at line `old_start` 
+/// and `new_start` of the complete texts, respectively.
+fn diff_range(old: &[&[u8]], new: &[&[u8]], old_start: usize, new_start: usize, 
+        edits: &mut Vec<Edit>) {
+    let prefix = old.iter().zip(new).take_while(|&(o, n)| o == n).count();
+    for i in 0 .. prefix {
+        edits.push(Edit::Keep(old_start + i, new_start + i));
+    }
+    let (old_rest, new_rest) = (&old[prefix ..], &new[prefix ..]);
+    let suffix = old_rest.iter().rev().zip(new_rest.iter().rev()).take_while(|&(o, n)| o == n)
+        .count();
+    let old_mid = &old_rest[.. old_rest.len() - suffix];
+    let new_mid = &new_rest[.. new_rest.len() - suffix];
+    let (old_mid_start, new_mid_start) = (old_start + prefix, new_start + prefix);
+
+    if old_mid.is_empty() {
+        for i in 0 .. new_mid.len() {
+            edits.push(Edit::Insert(new_mid_start + i));
+        }
+    } else if new_mid.is_empty() {
+        for i in 0 .. old_mid.len() {
+            edits.push(Edit::Delete(old_mid_start + i));
+        }
+    } else {
+        // Both halves are strictly smaller problems: the texts differ at both ends, so the 
+        // split cannot be at either end
+        let (x, y) = middle_snake(old_mid, new_mid);
+        diff_range(&old_mid[.. x], &new_mid[.. y], old_mid_start, new_mid_start, edits);
+        diff_range(&old_mid[x ..], &new_mid[y ..], old_mid_start + x, new_mid_start + y, edits);
+    }
+
+    for i in 0 .. suffix {
+        edits.push(Edit::Keep(old_start + old.len() - suffix + i, 
+            new_start + new.len() - suffix + i));
+    }
+}
+
+/// Finds a point (x, y) on a shortest edit path from `old` to `new` by searching forwards from 
+/// the start and backwards from the end at the same time until the two searches meet.
+fn middle_snake(old: &[&[u8]], new: &[&[u8]]) -> (usize, usize) {
+    let n = old.len() as isize;
+    let m = new.len() as isize;
+    let delta = n - m;
+    let odd = delta % 2 != 0;
+    let max = (n + m + 1) / 2;
+    let offset = max + 1;
+    // forward[k + offset] is the furthest x reached on diagonal k from the start, backward[k + 
+    // offset] the same for the reversed texts (diagonal k there is diagonal delta - k here)
+    let mut forward = vec![0isize; (2 * offset + 1) as usize];
+    let mut backward = vec![0isize; (2 * offset + 1) as usize];
+
+    for d in 0 .. max + 1 {
+        let mut k = -d;
+        while k <= d {
+            let idx = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && forward[idx - 1] < forward[idx + 1]) {
+                forward[idx + 1]
+            } else {
+                forward[idx - 1] + 1
+            };
+            let mut y = x - k;
+            let (x0, y0) = (x, y);
+            while x < n && y < m && y >= 0 && old[x as usize] == new[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            forward[idx] = x;
+            let reverse_k = delta - k;
+            if odd && reverse_k > -d && reverse_k < d 
+                    && x + backward[(reverse_k + offset) as usize] >= n {
+                return (x0 as usize, y0 as usize);
+            }
+            k += 2;
+        }
+
+        let mut k = -d;
+        while k <= d {
+            let idx = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && backward[idx - 1] < backward[idx + 1]) {
+                backward[idx + 1]
+            } else {
+                backward[idx - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && y >= 0 
+                    && old[(n - 1 - x) as usize] == new[(m - 1 - y) as usize] {
+                x += 1;
+                y += 1;
+            }
+            backward[idx] = x;
+            let forward_k = delta - k;
+            if !odd && forward_k >= -d && forward_k <= d 
+                    && x + forward[(forward_k + offset) as usize] >= n {
+                return ((n - x) as usize, (m - y) as usize);
+            }
+            k += 2;
+        }
+    }
+    unreachable!("The forward and backward searches always meet.")
+}
+
+fn write_line<W: Write>(out: &mut W, marker: u8, line: &[u8]) -> io::Result<()> {
+    out.write_all(&[marker])?;
+    out.write_all(line)?;
+    if !line.ends_with(b"\n") {
+        out.write_all(b"\n\\ No newline at end of file\n")?;
+    }
+    Ok(())
+}
+
+/// Writes a unified diff between `old` and `new` to `out`. Returns false (and writes nothing) if 
+/// both texts are identical.
+pub fn unified_diff<W: Write>(out: &mut W, old_name: &str, new_name: &str, old: &[u8], 
+        new: &[u8]) -> io::Result<bool> {
+    let old_lines = split_lines(old);
+    let new_lines = split_lines(new);
+    let edits = shortest_edit(&old_lines, &new_lines);
+
+    let changes : Vec<usize> = edits.iter().enumerate()
+        .filter(|&(_, e)| match *e { Edit::Keep(_,_) => false, _ => true })
+        .map(|(i, _)| i)
+        .collect();
+    if changes.is_empty() {
+        return Ok(false);
+    }
+
+    // Number of old and new lines that precede each edit
+    let mut positions = Vec::with_capacity(edits.len() + 1);
+    let (mut old_pos, mut new_pos) = (0, 0);
+    for edit in &edits {
+        positions.push((old_pos, new_pos));
+        match *edit {
+            Edit::Keep(_,_) => { old_pos += 1; new_pos += 1; },
+            Edit::Delete(_) => old_pos += 1,
+            Edit::Insert(_) => new_pos += 1
+        }
+    }
+    positions.push((old_pos, new_pos));
+
+    writeln!(out, "--- {}", old_name)?;
+    writeln!(out, "+++ {}", new_name)?;
+
+    let mut i = 0;
+    while i < changes.len() {
+        // Merge changes whose contexts would touch or overlap into a single hunk
+        let mut j = i;
+        while j + 1 < changes.len() && changes[j + 1] - changes[j] - 1 <= 2 * CONTEXT {
+            j += 1;
+        }
+        let start = changes[i].saturating_sub(CONTEXT);
+        let end = min(changes[j] + 1 + CONTEXT, edits.len());
+
+        let old_len = positions[end].0 - positions[start].0;
+        let new_len = positions[end].1 - positions[start].1;
+        // Empty ranges refer to the line before the hunk
+        let old_line = if old_len == 0 { positions[start].0 } else { positions[start].0 + 1 };
+        let new_line = if new_len == 0 { positions[start].1 } else { positions[start].1 + 1 };
+        writeln!(out, "@@ -{},{} +{},{} @@", old_line, old_len, new_line, new_len)?;
+
+        for edit in &edits[start .. end] {
+            match *edit {
+                Edit::Keep(o, _) => write_line(out, b' ', old_lines[o])?,
+                Edit::Delete(o) => write_line(out, b'-', old_lines[o])?,
+                Edit::Insert(n) => write_line(out, b'+', new_lines[n])?
+            }
+        }
+        i = j + 1;
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks that `edits` turn `old` into `new` and returns the number of inserted and deleted 
+    /// lines.
+    fn apply(old: &[&[u8]], new: &[&[u8]], edits: &[Edit]) -> usize {
+        let (mut old_pos, mut new_pos, mut cost) = (0, 0, 0);
+        for edit in edits {
+            match *edit {
+                Edit::Keep(o, n) => {
+                    assert_eq!((o, n), (old_pos, new_pos));
+                    assert_eq!(old[o], new[n]);
+                    old_pos += 1;
+                    new_pos += 1;
+                },
+                Edit::Delete(o) => {
+                    assert_eq!(o, old_pos);
+                    old_pos += 1;
+                    cost += 1;
+                },
+                Edit::Insert(n) => {
+                    assert_eq!(n, new_pos);
+                    new_pos += 1;
+                    cost += 1;
+                }
+            }
+        }
+        assert_eq!((old_pos, new_pos), (old.len(), new.len()));
+        cost
+    }
+
+    #[test]
+    fn shortest_edit_finds_the_minimal_number_of_changes() {
+        let old : Vec<&[u8]> = vec![b"a\n", b"b\n", b"c\n", b"a\n", b"b\n", b"b\n", b"a\n"];
+        let new : Vec<&[u8]> = vec![b"c\n", b"b\n", b"a\n", b"b\n", b"a\n", b"c\n"];
+        assert_eq!(apply(&old, &new, &shortest_edit(&old, &new)), 5);
+    }
+
+    /// Recording the whole search state for every step would take more than a GB here.
+    #[test]
+    fn large_texts_without_common_lines() {
+        let old : Vec<Vec<u8>> = (0 .. 5000).map(|i| format!("old {}\n", i).into_bytes()).collect();
+        let new : Vec<Vec<u8>> = (0 .. 5000).map(|i| format!("new {}\n", i).into_bytes()).collect();
+        let old : Vec<&[u8]> = old.iter().map(|line| &line[..]).collect();
+        let new : Vec<&[u8]> = new.iter().map(|line| &line[..]).collect();
+        assert_eq!(apply(&old, &new, &shortest_edit(&old, &new)), 10000);
+    }
+
+    #[test]
+    fn unified_diff_of_a_single_changed_line() {
+        let mut out = Vec::new();
+        let changed = unified_diff(&mut out, "a/doc.tex", "b/doc.tex", 
+            b"1\n2\n3\n4\n\\alpha\n6\n7\n8\n9\n", b"1\n2\n3\n4\n\xce\xb1\n6\n7\n8\n9\n").unwrap();
+        assert!(changed);
+        assert_eq!(String::from_utf8(out).unwrap(), concat!("--- a/doc.tex\n+++ b/doc.tex\n",
+            "@@ -2,7 +2,7 @@\n 2\n 3\n 4\n-\\alpha\n+\u{3b1}\n 6\n 7\n 8\n"));
+        assert!(!unified_diff(&mut Vec::new(), "a", "b", b"same\n", b"same\n").unwrap());
+    }
+}
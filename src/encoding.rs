@@ -0,0 +1,251 @@
+//! Character encodings other than UTF-8. The conversion itself always works on UTF-8: the input
+//! is decoded before the patterns are matched and the output is encoded afterwards.
+
+use std::io::{self, Cursor, Read};
+use std::str;
+
+use ::encoding_rs::{Decoder, DecoderResult, Encoder, EncoderResult, Encoding};
+
+use ::common::UniResult;
+use ::error::{code, UniError, UniErrorData};
+
+/// The UTF-8 encoding of U+FEFF, which some editors put at the start of a file.
+pub const BOM: &'static [u8] = b"\xEF\xBB\xBF";
+
+/// Size of the buffer for reading the undecoded input.
+const READ_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Looks up an encoding by one of its labels, e.g., `latin1`, `windows-1252` or `utf-16le`.
+pub fn from_label(label: &str) -> Option<&'static Encoding> {
+    Encoding::for_label(label.trim().as_bytes())
+}
+
+/// What happens to a byte order mark at the start of the input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BomMode {
+    /// Pass it through like any other text
+    Keep,
+    /// Remove it
+    Strip,
+    /// Make sure the output starts with exactly one byte order mark
+    Add
+}
+
+impl BomMode {
+    /// Parses the name used on the command line.
+    pub fn from_name(name: &str) -> Option<BomMode> {
+        match name {
+            "keep" => Some(BomMode::Keep),
+            "strip" => Some(BomMode::Strip),
+            "add" => Some(BomMode::Add),
+            _ => None
+        }
+    }
+}
+
+impl Default for BomMode {
+    fn default() -> BomMode {
+        BomMode::Keep
+    }
+}
+
+/// Removes a byte order mark from the start of the (UTF-8) stream `input`, if there is one. 
+pub fn strip_bom<R: Read>(mut input: R) -> io::Result<io::Chain<Cursor<Vec<u8>>, R>> {
+    let mut head = Vec::with_capacity(BOM.len());
+    while head.len() < BOM.len() {
+        let mut byte = [0; 1];
+        match input.read(&mut byte) {
+            Ok(0) => break,
+            Ok(_) => head.push(byte[0]),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e)
+        }
+        if !BOM.starts_with(&head) {
+            break;
+        }
+    }
+    if head == BOM {
+        debug!("Removing byte order mark");
+        head.clear();
+    }
+    Ok(Cursor::new(head).chain(input))
+}
+
+/// Decodes a stream in the given encoding to UTF-8. Malformed input fails the read with an
+/// encoding error (wrapped in an `io::Error`, see `UniError::from_io`).
+pub struct DecodingReader<R: Read> {
+    inner: R,
+    decoder: Decoder,
+    raw: Vec<u8>,
+    /// Decoded bytes that haven't been read yet, starting at `decoded_pos`
+    decoded: Vec<u8>,
+    decoded_pos: usize,
+    /// Number of input bytes decoded so far, for error messages
+    offset: u64,
+    finished: bool
+}
+
+impl<R: Read> DecodingReader<R> {
+    pub fn new(inner: R, encoding: &'static Encoding) -> DecodingReader<R> {
+        DecodingReader {
+            inner,
+            // Byte order marks are left to the rest of the conversion
+            decoder: encoding.new_decoder_without_bom_handling(),
+            raw: vec![0; READ_BUFFER_SIZE],
+            decoded: Vec::new(),
+            decoded_pos: 0,
+            offset: 0,
+            finished: false
+        }
+    }
+
+    /// Reads and decodes the next piece of the input.
+    fn fill(&mut self) -> io::Result<()> {
+        let len = self.inner.read(&mut self.raw)?;
+        let last = len == 0;
+        let capacity = self.decoder.max_utf8_buffer_length_without_replacement(len)
+            .unwrap_or(len * 4 + 16);
+        self.decoded.clear();
+        self.decoded.resize(capacity, 0);
+        self.decoded_pos = 0;
+        let (result, read, written) = self.decoder.decode_to_utf8_without_replacement(
+            &self.raw[.. len], &mut self.decoded, last);
+        self.decoded.truncate(written);
+        match result {
+            DecoderResult::InputEmpty => (),
+            DecoderResult::Malformed(_, _) => {
+                let error = UniError::new(code::encoding::INPUT, UniErrorData::Encoding(format!(
+                    "The input is not valid {} (near byte {}).", self.decoder.encoding().name(),
+                    self.offset + read as u64)));
+                return Err(io::Error::new(io::ErrorKind::InvalidData, error));
+            },
+            DecoderResult::OutputFull => return Err(io::Error::new(io::ErrorKind::Other,
+                UniError::new(code::internal::MISC, UniErrorData::Internal(
+                    "The buffer for the decoded input is too small.".to_owned()))))
+        }
+        self.offset += len as u64;
+        self.finished = last;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecodingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.decoded_pos == self.decoded.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            self.fill()?;
+        }
+        let len = ::std::cmp::min(buf.len(), self.decoded.len() - self.decoded_pos);
+        buf[.. len].copy_from_slice(&self.decoded[self.decoded_pos .. self.decoded_pos + len]);
+        self.decoded_pos += len;
+        Ok(len)
+    }
+}
+
+/// Checks that a stream that arrives in arbitrary pieces is valid UTF-8.
+#[derive(Debug, Default)]
+pub struct Utf8Validator {
+    /// The start of a UTF-8 sequence whose remaining bytes are still to come
+    held: Vec<u8>,
+    /// Number of bytes validated so far, for error messages
+    offset: u64
+}
+
+impl Utf8Validator {
+    pub fn new() -> Utf8Validator {
+        Utf8Validator { held: Vec::new(), offset: 0 }
+    }
+
+    /// Adds `bytes` to the stream. Fails at the first invalid sequence.
+    pub fn push(&mut self, bytes: &[u8]) -> UniResult<()> {
+        let mut rest = bytes;
+        // Complete the character started by the previous piece first
+        while !self.held.is_empty() && !rest.is_empty() {
+            self.held.push(rest[0]);
+            rest = &rest[1 ..];
+            match str::from_utf8(&self.held) {
+                Ok(_) => {
+                    self.offset += self.held.len() as u64;
+                    self.held.clear();
+                },
+                Err(ref e) if e.error_len().is_none() => (),
+                Err(_) => return Err(self.invalid(0))
+            }
+        }
+        match str::from_utf8(rest) {
+            Ok(_) => self.offset += rest.len() as u64,
+            Err(ref e) if e.error_len().is_none() => {
+                self.offset += e.valid_up_to() as u64;
+                self.held.extend_from_slice(&rest[e.valid_up_to() ..]);
+            },
+            Err(ref e) => return Err(self.invalid(e.valid_up_to()))
+        }
+        Ok(())
+    }
+
+    /// Ends the stream. Fails if it ends in the middle of a character.
+    pub fn finish(&mut self) -> UniResult<()> {
+        if self.held.is_empty() { Ok(()) } else { Err(self.invalid(0)) }
+    }
+
+    fn invalid(&self, valid_len: usize) -> UniError {
+        UniError::new(code::encoding::INVALID_UTF8, UniErrorData::Encoding(format!(concat!(
+            "The input is not valid UTF-8 (near byte {}). Use --input-encoding for inputs in ",
+            "other encodings."), self.offset + valid_len as u64)))
+    }
+}
+
+/// Encodes UTF-8 output that arrives in arbitrary pieces. Characters that the encoding lacks
+/// fail the conversion.
+pub struct OutputEncoder {
+    encoder: Encoder,
+    /// The start of a UTF-8 sequence whose remaining bytes are still to come
+    held: Vec<u8>
+}
+
+impl OutputEncoder {
+    /// Fails for encodings that can only be decoded (e.g., UTF-16).
+    pub fn new(encoding: &'static Encoding) -> UniResult<OutputEncoder> {
+        if encoding.output_encoding() != encoding {
+            return Err(UniError::new(code::encoding::OUTPUT, UniErrorData::Encoding(format!(
+                "Cannot write {}, it is only supported for the input.", encoding.name()))));
+        }
+        Ok(OutputEncoder { encoder: encoding.new_encoder(), held: Vec::new() })
+    }
+
+    /// Encodes `bytes` and appends the result to `out`. With `last`, the stream ends here.
+    pub fn push(&mut self, bytes: &[u8], out: &mut Vec<u8>, last: bool) -> UniResult<()> {
+        self.held.extend_from_slice(bytes);
+        let valid = match str::from_utf8(&self.held) {
+            Ok(_) => self.held.len(),
+            Err(ref e) if e.error_len().is_none() && !last => e.valid_up_to(),
+            Err(_) => return Err(UniError::new(code::encoding::OUTPUT, UniErrorData::Encoding(
+                format!(concat!("Cannot write the output as {}: it is not valid UTF-8. ",
+                    "Use --input-encoding for inputs in other encodings."), 
+                    self.encoder.encoding().name()))))
+        };
+        {
+            let text = str::from_utf8(&self.held[.. valid]).unwrap();
+            let start = out.len();
+            let capacity = self.encoder.max_buffer_length_from_utf8_without_replacement(text.len())
+                .unwrap_or(text.len() * 4 + 16);
+            out.resize(start + capacity, 0);
+            let (result, _, written) = self.encoder.encode_from_utf8_without_replacement(
+                text, &mut out[start ..], last);
+            out.truncate(start + written);
+            match result {
+                EncoderResult::InputEmpty => (),
+                EncoderResult::Unmappable(c) => return Err(UniError::new(code::encoding::OUTPUT,
+                    UniErrorData::Encoding(format!("{:?} (U+{:04X}) cannot be written as {}.",
+                        c, c as u32, self.encoder.encoding().name())))),
+                EncoderResult::OutputFull => return Err(UniError::new(code::internal::MISC,
+                    UniErrorData::Internal("The buffer for the encoded output is too small."
+                        .to_owned())))
+            }
+        }
+        self.held.drain(.. valid);
+        Ok(())
+    }
+}
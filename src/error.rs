@@ -1,9 +1,10 @@
 
-use std::io;
+use std::io::{self, Write};
 use std::fmt::{self,Display, Debug};
 use std::error::{Error};
 
 use ::yaml;
+use ::toml;
 
 /// Error type for the to-uni program.
 pub struct UniError {
@@ -25,6 +26,19 @@ impl UniError {
         self.code_major*10 + self.code_minor
     }
 
+    pub fn code_major(&self) -> u8 {
+        self.code_major
+    }
+
+    pub fn code_minor(&self) -> u8 {
+        self.code_minor
+    }
+
+    /// Machine-readable name of the kind of error, e.g., for JSON error reports.
+    pub fn kind(&self) -> &'static str {
+        self.data.kind()
+    }
+
     pub fn new(minor: u8, data: UniErrorData) -> UniError {
         let (major,_) = data.default_code_major_minor();
         UniError {
@@ -32,10 +46,41 @@ impl UniError {
         }
     }
 
+    /// Error for a failed read or write. Some streams (e.g., for other character encodings) 
+    /// report their own errors wrapped in an `io::Error`; those are passed on as they are.
+    pub fn from_io(minor: u8, err: io::Error) -> UniError {
+        if err.get_ref().map_or(false, |inner| inner.is::<UniError>()) {
+            if let Some(inner) = err.into_inner() {
+                if let Ok(uni_error) = inner.downcast::<UniError>() {
+                    return *uni_error;
+                }
+            }
+            unreachable!("The wrapped error was checked to be a UniError.");
+        }
+        UniError::new(minor, UniErrorData::Io(err))
+    }
+
     pub fn with_minor(mut self, minor: u8) -> Self {
         self.code_minor = minor;
         self
     }
+
+    /// Whether this error is a write to a pipe whose reader has gone away (e.g., `| head`).
+    pub fn is_broken_pipe(&self) -> bool {
+        match self.data {
+            UniErrorData::Io(ref e) | UniErrorData::FsIo(_, ref e) => 
+                e.kind() == io::ErrorKind::BrokenPipe,
+            _ => false
+        }
+    }
+
+    /// Whether this error only signals that a `--check` found something to convert.
+    pub fn is_check_failure(&self) -> bool {
+        match self.data {
+            UniErrorData::CheckFailed(_) => true,
+            _ => false
+        }
+    }
 }
 
 pub mod code {
@@ -48,18 +93,114 @@ pub mod code {
     pub mod internal {
         pub static MISC: u8 = 8;
     }
+    pub mod encoding {
+        pub static INPUT: u8 = 1;
+        pub static OUTPUT: u8 = 2;
+        pub static INVALID_UTF8: u8 = 3;
+    }
+    pub mod conversion {
+        pub static NO_TRANSLITERATION: u8 = 1;
+        pub static QUIT: u8 = 2;
+    }
     pub mod usage {
+        /// The default for usage errors, e.g., an option with an invalid value
+        pub static INVALID_ARGUMENT: u8 = 1;
+        pub static MALFORMED_PATTERN: u8 = 2;
+        pub static NO_BACKUP_FILE: u8 = 3;
+        /// No output file name: there is no input file to take it from
         pub static MISSING_OUTPUT_FILE_NAME: u8 = 4;
-        pub static MISSING_OUTPUT: u8 =  5;
+        /// The output file exists and --force is not given. Used to be the file system error 26; 
+        /// the code was freed by merging "no output given" into `MISSING_OUTPUT_FILE_NAME`.
+        pub static OUTPUT_EXISTS: u8 = 5;
         pub static INPUT_NOT_A_FILE: u8 = 6;
         pub static NO_CONFIG_FILE: u8 = 7;
         pub static INVALID_CONFIG_FILE: u8 = 8;
+        pub static CONFLICTING_OPTIONS: u8 = 9;
     }
 }
 
+/// An exit code along with the situation it stands for, see `known_codes`.
+#[derive(Debug, Serialize)]
+pub struct KnownCode {
+    pub error_code: u8,
+    pub code_major: u8,
+    pub code_minor: u8,
+    pub kind: &'static str,
+    pub description: &'static str
+}
+
+/// All combinations of major and minor codes that to-uni reports, sorted by exit code.
+pub fn known_codes() -> Vec<KnownCode> {
+    let entries : Vec<(UniErrorData, u8, &'static str)> = vec![
+        (usage_data(), code::usage::INVALID_ARGUMENT, "Invalid command line"),
+        (usage_data(), code::usage::MALFORMED_PATTERN, "Malformed pattern (--pattern)"),
+        (usage_data(), code::usage::NO_BACKUP_FILE, "No backup file to restore"),
+        (usage_data(), code::usage::MISSING_OUTPUT_FILE_NAME, 
+            "No input file to name the output after"),
+        (usage_data(), code::usage::OUTPUT_EXISTS, "Output file exists (use --force)"),
+        (usage_data(), code::usage::INPUT_NOT_A_FILE, "Input is not a file"),
+        (usage_data(), code::usage::NO_CONFIG_FILE, "No configuration file found"),
+        (usage_data(), code::usage::INVALID_CONFIG_FILE, "Invalid configuration file"),
+        (usage_data(), code::usage::CONFLICTING_OPTIONS, "Conflicting options"),
+        (UniErrorData::Io(io_data()), 0, "General IO error"),
+        (UniErrorData::Io(io_data()), code::fsio::INPUT, "Cannot read the input while converting"),
+        (UniErrorData::Io(io_data()), code::fsio::OUTPUT, 
+            "Cannot write the output while converting"),
+        (fs_io_data(), code::fsio::INPUT, "Cannot read the input"),
+        (fs_io_data(), code::fsio::OUTPUT, "Cannot write the output"),
+        (fs_io_data(), code::fsio::OUTPUT_BACKUP, "Cannot create or restore a backup"),
+        (fs_io_data(), code::fsio::CONFIG, "Cannot read the configuration file"),
+        (UniErrorData::YamlScan(String::new(), yaml_data()), 0, "Malformed YAML"),
+        (UniErrorData::TomlParse(String::new(), toml_data()), 0, "Malformed TOML"),
+        (UniErrorData::CheckFailed(Vec::new()), 0, "Input needs conversion (--check)"),
+        (UniErrorData::Encoding(String::new()), code::encoding::INPUT, 
+            "Input is not valid in its encoding"),
+        (UniErrorData::Encoding(String::new()), code::encoding::OUTPUT, 
+            "Output cannot be written in the requested encoding"),
+        (UniErrorData::Encoding(String::new()), code::encoding::INVALID_UTF8, 
+            "Input is not valid UTF-8 (--check-utf8)"),
+        (UniErrorData::Conversion(String::new()), code::conversion::NO_TRANSLITERATION, 
+            "No transliteration for a replacement (--translit-missing error)"),
+        (UniErrorData::Conversion(String::new()), code::conversion::QUIT, 
+            "Stopped by the user (--interactive)"),
+        (UniErrorData::Internal(String::new()), code::internal::MISC, "Internal error")
+    ];
+    entries.into_iter().map(|(data, minor, description)| {
+        let error = UniError::new(minor, data);
+        KnownCode {
+            error_code: error.error_code(),
+            code_major: error.code_major(),
+            code_minor: error.code_minor(),
+            kind: error.kind(),
+            description
+        }
+    }).collect()
+}
+
+fn usage_data() -> UniErrorData {
+    UniErrorData::Usage(String::new())
+}
+
+fn io_data() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "")
+}
+
+fn fs_io_data() -> UniErrorData {
+    UniErrorData::FsIo(String::new(), io_data())
+}
+
+// The parsers' errors cannot be constructed directly, so they come from malformed documents
+fn yaml_data() -> yaml::ScanError {
+    yaml::YamlLoader::load_from_str("[").unwrap_err()
+}
+
+fn toml_data() -> toml::de::Error {
+    "=".parse::<toml::Value>().unwrap_err()
+}
+
 pub fn usage(message: String) -> UniError {
     let data = UniErrorData::Usage(message);
-    let (minor,major) = data.default_code_major_minor();
+    let (major,minor) = data.default_code_major_minor();
     UniError {
         code_minor: minor, code_major: major, data
     }
@@ -75,7 +216,15 @@ pub enum UniErrorData {
     Internal(String),
     Usage(String),
     /// YAML file path
-    YamlScan(String, yaml::ScanError)
+    YamlScan(String, yaml::ScanError),
+    /// TOML file path
+    TomlParse(String, toml::de::Error),
+    /// Inputs that would be changed by a conversion (--check)
+    CheckFailed(Vec<String>),
+    /// Text that cannot be decoded from or encoded in the requested character encoding
+    Encoding(String),
+    /// The input could not be converted as requested
+    Conversion(String)
 }
 
 impl UniErrorData {
@@ -85,48 +234,81 @@ impl UniErrorData {
             UniErrorData::FsIo(_,_) => (2,0),
             UniErrorData::Internal(_) => (9,0),
             UniErrorData::Usage(_) => (0,1),
-            UniErrorData::YamlScan(_,_) => (3,0)
+            UniErrorData::YamlScan(_,_) => (3,0),
+            UniErrorData::TomlParse(_,_) => (4,0),
+            UniErrorData::CheckFailed(_) => (5,0),
+            UniErrorData::Encoding(_) => (6,0),
+            UniErrorData::Conversion(_) => (7,0)
         }
     }
-}
 
-impl Error for UniError {
-    fn description(&self) -> &str {
-        match self.data {
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            UniErrorData::Io(_) => "io",
+            UniErrorData::FsIo(_,_) => "fs_io",
+            UniErrorData::Internal(_) => "internal",
+            UniErrorData::Usage(_) => "usage",
+            UniErrorData::YamlScan(_,_) => "yaml_scan",
+            UniErrorData::TomlParse(_,_) => "toml_parse",
+            UniErrorData::CheckFailed(_) => "check_failed",
+            UniErrorData::Encoding(_) => "encoding",
+            UniErrorData::Conversion(_) => "conversion"
+        }
+    }
+
+    /// Short human readable description of the kind of error. Starts every error message.
+    fn summary(&self) -> &'static str {
+        match *self {
             UniErrorData::Io(_) => "General IO error.",
             UniErrorData::FsIo(_,_) => "File system IO error.",
             UniErrorData::Internal(_) => "Internal error.",
             UniErrorData::Usage(_) => "Usage error.",
-            UniErrorData::YamlScan(_,_) => "YAML parsing error."
+            UniErrorData::YamlScan(_,_) => "YAML parsing error.",
+            UniErrorData::TomlParse(_,_) => "TOML parsing error.",
+            UniErrorData::CheckFailed(_) => "Check failed.",
+            UniErrorData::Encoding(_) => "Encoding error.",
+            UniErrorData::Conversion(_) => "Conversion error."
         }
     }
-    fn cause(&self) -> Option<&Error> {
+}
+
+impl Error for UniError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
         match self.data {
             UniErrorData::Io(ref e) => Some(e),
             UniErrorData::FsIo(_, ref e) => Some(e),
             UniErrorData::Internal(_) => None,
             UniErrorData::Usage(_) => None,
-            UniErrorData::YamlScan(_, ref e) => Some(e)
+            UniErrorData::YamlScan(_, ref e) => Some(e),
+            UniErrorData::TomlParse(_, ref e) => Some(e),
+            UniErrorData::CheckFailed(_) => None,
+            UniErrorData::Encoding(_) => None,
+            UniErrorData::Conversion(_) => None
         }
     }
 }
 
 impl Display for UniError {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        write!(f, "{} ", self.description())?;
+        write!(f, "{} ", self.data.summary())?;
         match self.data {
             UniErrorData::Io(ref e) => write!(f, "{}", e),
             UniErrorData::FsIo(ref path, ref e) => write!(f, "{} Path: {}", e, path),
             UniErrorData::Internal(ref m) => write!(f, "{}", m),
             UniErrorData::Usage(ref m) => write!(f, "{}", m),
-            UniErrorData::YamlScan(ref path, ref e) => write!(f, "{} Path: {}", e, path)
+            UniErrorData::YamlScan(ref path, ref e) => write!(f, "{} Path: {}", e, path),
+            UniErrorData::TomlParse(ref path, ref e) => write!(f, "{} Path: {}", e, path),
+            UniErrorData::CheckFailed(ref inputs) => 
+                write!(f, "Needs conversion: {}", inputs.join(", ")),
+            UniErrorData::Encoding(ref m) => write!(f, "{}", m),
+            UniErrorData::Conversion(ref m) => write!(f, "{}", m)
         }
     }
 }
 
 impl Debug for UniError {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        write!(f, "{} Code: {} Data: {:?}", self.description(), self.error_code(), self.data)
+        write!(f, "{} Code: {} Data: {:?}", self.data.summary(), self.error_code(), self.data)
     }
 }
 
@@ -212,3 +394,128 @@ impl DetailedFrom<yaml::ScanError, String> for UniError {
         }
     }
 }
+
+impl DetailedFrom<toml::de::Error, String> for UniError {
+    fn detailed_from(err: toml::de::Error, path: String) -> UniError {
+        let data = UniErrorData::TomlParse(path, err);
+        let (major,minor) = data.default_code_major_minor();
+        UniError {
+            code_major: major, code_minor: minor, data
+        }
+    }
+}
+
+/// Prints the table of `known_codes` to standard output.
+pub fn list_codes(json: bool) -> ::common::UniResult<()> {
+    let stdout = io::stdout();
+    try_!(write_codes(&mut stdout.lock(), json), "<stdout>".to_string(), code::fsio::OUTPUT);
+    Ok(())
+}
+
+/// Writes the table of `known_codes` (or a JSON array of them) to `out`.
+fn write_codes(out: &mut Write, json: bool) -> io::Result<()> {
+    let codes = known_codes();
+    if json {
+        ::serde_json::to_writer_pretty(&mut *out, &codes).map_err(io::Error::from)?;
+        writeln!(out)
+    } else {
+        for known in &codes {
+            writeln!(out, "{:>3}  ({}, {})  {:<12}  {}", known.error_code, known.code_major, 
+                known.code_minor, known.kind, known.description)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn known(error: &UniError) -> bool {
+        known_codes().iter().any(|known| 
+            (known.code_major, known.code_minor) == (error.code_major(), error.code_minor()))
+    }
+
+    /// Errors of every kind, constructed the way to-uni reports them.
+    fn reported_errors() -> Vec<UniError> {
+        let mut errors = vec![usage(String::new())];
+        for &minor in &[code::usage::INVALID_ARGUMENT, code::usage::MALFORMED_PATTERN, 
+                code::usage::NO_BACKUP_FILE, code::usage::MISSING_OUTPUT_FILE_NAME, 
+                code::usage::OUTPUT_EXISTS, code::usage::INPUT_NOT_A_FILE, 
+                code::usage::NO_CONFIG_FILE, code::usage::INVALID_CONFIG_FILE, 
+                code::usage::CONFLICTING_OPTIONS] {
+            errors.push(usage(String::new()).with_minor(minor));
+        }
+        errors.push(io_data().into());
+        for &minor in &[code::fsio::INPUT, code::fsio::OUTPUT] {
+            errors.push(UniError::from_io(minor, io_data()));
+        }
+        for &minor in &[code::fsio::INPUT, code::fsio::OUTPUT, code::fsio::OUTPUT_BACKUP, 
+                code::fsio::CONFIG] {
+            errors.push(from_!(io_data(), ("doc.tex".to_owned(), minor)));
+        }
+        errors.push(from_!(yaml_data(), "to-uni.yml".to_owned()));
+        errors.push(from_!(toml_data(), "to-uni.toml".to_owned()));
+        errors.push(UniError::new(0, UniErrorData::CheckFailed(vec!["doc.tex".to_owned()])));
+        for &minor in &[code::encoding::INPUT, code::encoding::OUTPUT, 
+                code::encoding::INVALID_UTF8] {
+            errors.push(UniError::new(minor, UniErrorData::Encoding(String::new())));
+        }
+        for &minor in &[code::conversion::NO_TRANSLITERATION, code::conversion::QUIT] {
+            errors.push(UniError::new(minor, UniErrorData::Conversion(String::new())));
+        }
+        errors.push(from_!("internal".to_owned(), code::internal::MISC));
+        errors
+    }
+
+    #[test]
+    fn every_reported_error_has_a_known_code() {
+        for error in reported_errors() {
+            assert!(known(&error), "known_codes() is missing {} ({}, {})", error.kind(), 
+                error.code_major(), error.code_minor());
+        }
+    }
+
+    #[test]
+    fn usage_errors_have_major_code_zero() {
+        assert_eq!(usage(String::new()).error_code(), 1);
+        assert_eq!(usage(String::new()).with_minor(code::usage::NO_CONFIG_FILE).error_code(), 7);
+    }
+
+    #[test]
+    fn known_codes_are_unique() {
+        let codes = known_codes();
+        for (i, known) in codes.iter().enumerate() {
+            assert!(codes[i + 1 ..].iter().all(|other| other.error_code != known.error_code),
+                "Exit code {} is listed twice", known.error_code);
+        }
+    }
+
+    #[test]
+    fn list_codes_prints_the_table() {
+        let mut table = Vec::new();
+        write_codes(&mut table, false).unwrap();
+        let table = String::from_utf8(table).unwrap();
+        assert_eq!(table.lines().count(), known_codes().len());
+        assert!(table.lines().any(|line| 
+            line == "  5  (0, 5)  usage         Output file exists (use --force)"), "{}", table);
+        assert!(table.lines().any(|line| line.starts_with(" 30  (3, 0)  yaml_scan ")), "{}", table);
+
+        let mut json = Vec::new();
+        write_codes(&mut json, true).unwrap();
+        let json : ::serde_json::Value = ::serde_json::from_slice(&json).unwrap();
+        let entries = json.as_array().expect("list-codes --json prints an array");
+        assert_eq!(entries.len(), known_codes().len());
+        assert!(entries.iter().any(|entry| entry["error_code"].as_u64() == Some(13) 
+            && entry["code_major"].as_u64() == Some(1) && entry["code_minor"].as_u64() == Some(3) 
+            && entry["kind"].as_str() == Some("io")));
+    }
+
+    #[test]
+    fn errors_while_converting_have_known_codes() {
+        let error = UniError::from_io(code::fsio::OUTPUT, io::Error::new(io::ErrorKind::Other, 
+            "disk full"));
+        assert_eq!(error.error_code(), 13);
+        assert!(known_codes().iter().any(|known| known.error_code == 13));
+    }
+}
@@ -0,0 +1,48 @@
+//! to-uni scans for LaTeX-style escape sequences and replaces them with their unicode 
+//! counterpart. The conversion engine is available as a library via `convert_str` and 
+//! `convert_stream`; the `to-uni` binary is a thin command line wrapper around it.
+
+// This is required to not warn on DetailedFrom::detailed_from used with
+// a single-element-tuple.
+#![allow(unused_parens)]
+
+#[macro_use]
+extern crate log;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate env_logger;
+extern crate yaml_rust as yaml;
+extern crate toml;
+extern crate atomicwrites;
+extern crate aho_corasick;
+extern crate unicode_normalization;
+extern crate encoding_rs;
+extern crate memmap;
+extern crate filetime;
+extern crate flate2;
+extern crate ctrlc;
+#[cfg(test)]
+extern crate docopt;
+
+pub mod common;
+#[macro_use]
+pub mod error;
+pub mod builtin;
+pub mod config;
+pub mod conversion;
+pub mod regions;
+pub mod normalization;
+pub mod translit;
+pub mod format;
+pub mod encoding;
+pub mod completions;
+pub mod progress;
+mod diff;
+#[cfg(test)]
+mod testing;
+
+pub use common::UniResult;
+pub use error::UniError;
+pub use conversion::{Converter, ConversionStats, convert_str, convert_stream};
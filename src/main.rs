@@ -1,40 +1,40 @@
-
-// This is required to not warn on DetailedFrom::detailed_from used with
-// a single-element-tuple.
-#![allow(unused_parens)]
-
 #[macro_use]
 extern crate log;
 extern crate docopt;
-extern crate serde;
-#[macro_use]
-extern crate serde_derive;
-extern crate env_logger;
-extern crate yaml_rust as yaml;
-extern crate atomicwrites;
-extern crate aho_corasick;
-
-mod common;
-#[macro_use]
-mod error;
-mod config;
-mod conversion;
+extern crate to_uni;
 
 use docopt::Docopt;
 
+use to_uni::common;
+use to_uni::config;
+use to_uni::error;
+use to_uni::conversion;
+
 fn main() {
-    common::init();
     // the docopt::Error::exit method automatically prints help (and version) as appropriate
     let args: config::Args = Docopt::new(config::USAGE).and_then(|d| 
           d.help(true)
               .version(Some(String::from(common::TO_UNI_VERSION)))
               .deserialize())
         .unwrap_or_else(|e| e.exit());
+    common::init(args.log_level());
     debug!("Command line arguments: {:#?}", args);
-
-    common::handle_program_exit(
-        config::Configuration::from_args(args).and_then(|c| conversion::run(&c))
-    );
+    let json_errors = args.json_errors();
+
+    common::handle_program_exit(config::Command::from_args(args).and_then(|command| 
+        match command {
+            config::Command::Convert(c) => conversion::run(&c),
+            config::Command::Restore(paths, backup) => paths.iter()
+                .map(|path| config::Output::restore_backup(path, &backup))
+                .collect(),
+            config::Command::ListPatterns(c, json) => conversion::list_patterns(&c, json),
+            config::Command::Validate(c) => conversion::validate(&c),
+            config::Command::Init(path, force) => config::write_config_template(&path, force),
+            config::Command::Completions(script) => {
+                print!("{}", script);
+                Ok(())
+            },
+            config::Command::ListCodes(json) => error::list_codes(json)
+        }
+    ), json_errors);
 }
-
-
@@ -0,0 +1,164 @@
+//! Normalization of the converted text. Replacements might use precomposed characters or 
+//! combining sequences; Unicode normalization makes them consistent. Line endings can be unified 
+//! as well.
+
+use ::std::str;
+
+use ::unicode_normalization::UnicodeNormalization;
+use ::unicode_normalization::char::canonical_combining_class;
+
+/// Lines longer than this are normalized in pieces, split before a character that does not
+/// combine with the preceding one.
+const MAX_HELD: usize = 64 * 1024;
+
+/// The Unicode normalization form of the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Normalization {
+    /// Canonical composition (precomposed characters where possible)
+    Nfc,
+    /// Canonical decomposition (base characters followed by combining marks)
+    Nfd
+}
+
+impl Normalization {
+    /// Parses the name used on the command line.
+    pub fn from_name(name: &str) -> Option<Normalization> {
+        match name {
+            "nfc" => Some(Normalization::Nfc),
+            "nfd" => Some(Normalization::Nfd),
+            _ => None
+        }
+    }
+}
+
+/// Normalizes a stream of bytes that arrives in arbitrary pieces. Text is held back until the
+/// end of the line (or of the input), so combining marks are never separated from their base
+/// character. Bytes that are not valid UTF-8 are passed through unchanged.
+pub struct Normalizer {
+    form: Normalization,
+    held: Vec<u8>
+}
+
+impl Normalizer {
+    pub fn new(form: Normalization) -> Normalizer {
+        Normalizer { form, held: Vec::new() }
+    }
+
+    /// Adds `bytes` to the stream and appends the text that is ready to `out`.
+    pub fn push(&mut self, bytes: &[u8], out: &mut Vec<u8>) {
+        self.held.extend_from_slice(bytes);
+        let split = match self.held.iter().rposition(|&b| b == b'\n') {
+            Some(newline) => newline + 1,
+            None if self.held.len() > MAX_HELD => self.last_starter(),
+            None => 0
+        };
+        if split > 0 {
+            normalize(self.form, &self.held[.. split], out);
+            self.held.drain(.. split);
+        }
+    }
+
+    /// Appends the rest of the stream to `out`.
+    pub fn finish(&mut self, out: &mut Vec<u8>) {
+        normalize(self.form, &self.held, out);
+        self.held.clear();
+    }
+
+    /// Offset of the last character in the held text that does not combine with its predecessor.
+    fn last_starter(&self) -> usize {
+        let valid = match str::from_utf8(&self.held) {
+            Ok(text) => text,
+            Err(e) => str::from_utf8(&self.held[.. e.valid_up_to()]).unwrap()
+        };
+        // Hangul syllables are composed from several starters, so the split has to come before a
+        // character that is not part of one
+        valid.char_indices().rev()
+            .find(|&(_, c)| canonical_combining_class(c) == 0 && !is_hangul_jamo(c))
+            .map_or(0, |(offset, _)| offset)
+    }
+}
+
+fn is_hangul_jamo(c: char) -> bool {
+    ('\u{1100}' <= c && c <= '\u{11FF}') || ('\u{AC00}' <= c && c <= '\u{D7A3}')
+}
+
+fn normalize(form: Normalization, bytes: &[u8], out: &mut Vec<u8>) {
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        let (valid_len, invalid_len) = match str::from_utf8(rest) {
+            Ok(_) => (rest.len(), 0),
+            Err(e) => (e.valid_up_to(), e.error_len().unwrap_or(rest.len() - e.valid_up_to()))
+        };
+        let text = str::from_utf8(&rest[.. valid_len]).unwrap();
+        match form {
+            Normalization::Nfc => out.extend(text.nfc().collect::<String>().bytes()),
+            Normalization::Nfd => out.extend(text.nfd().collect::<String>().bytes())
+        }
+        out.extend_from_slice(&rest[valid_len .. valid_len + invalid_len]);
+        rest = &rest[valid_len + invalid_len ..];
+    }
+}
+
+/// Line endings of the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineEndings {
+    /// Keep the line endings of the input, whatever they are
+    Preserve,
+    /// `\n`
+    Lf,
+    /// `\r\n`
+    Crlf
+}
+
+impl LineEndings {
+    /// Parses the name used on the command line.
+    pub fn from_name(name: &str) -> Option<LineEndings> {
+        match name {
+            "preserve" => Some(LineEndings::Preserve),
+            "lf" => Some(LineEndings::Lf),
+            "crlf" => Some(LineEndings::Crlf),
+            _ => None
+        }
+    }
+}
+
+impl Default for LineEndings {
+    fn default() -> LineEndings {
+        LineEndings::Preserve
+    }
+}
+
+/// Rewrites `\r\n` and `\n` line endings in a stream that arrives in arbitrary pieces. A lone 
+/// `\r` is not a line ending and left alone.
+pub struct LineEndingConverter {
+    target: LineEndings,
+    /// Whether the previous byte is a `\r`. When converting to `\n`, it has been held back.
+    after_cr: bool
+}
+
+impl LineEndingConverter {
+    pub fn new(target: LineEndings) -> LineEndingConverter {
+        LineEndingConverter { target, after_cr: false }
+    }
+
+    /// Adds `bytes` to the stream and appends the converted text to `out`. With `last`, the 
+    /// stream ends here.
+    pub fn push(&mut self, bytes: &[u8], out: &mut Vec<u8>, last: bool) {
+        out.reserve(bytes.len());
+        for &b in bytes {
+            match self.target {
+                LineEndings::Lf if self.after_cr && b != b'\n' => out.push(b'\r'),
+                LineEndings::Crlf if b == b'\n' && !self.after_cr => out.push(b'\r'),
+                _ => ()
+            }
+            self.after_cr = b == b'\r';
+            if !(self.after_cr && self.target == LineEndings::Lf) {
+                out.push(b);
+            }
+        }
+        if last && self.after_cr && self.target == LineEndings::Lf {
+            out.push(b'\r');
+            self.after_cr = false;
+        }
+    }
+}
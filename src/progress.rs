@@ -0,0 +1,63 @@
+//! Progress reports for long conversions, printed to standard error.
+
+use std::cmp::min;
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+/// Time between two reports, in milliseconds.
+const REPORT_INTERVAL_MS: u64 = 200;
+
+/// Shown for streams of unknown size, one character per report.
+const SPINNER: &'static [char] = &['|', '/', '-', '\\'];
+
+/// Passes a stream through and reports how much of it has been read. Each report overwrites the 
+/// previous one; the last one is completed with a line break when the reader is dropped.
+pub struct ProgressReader<R: Read> {
+    inner: R,
+    name: String,
+    /// Size of the stream, if known
+    total: Option<u64>,
+    read: u64,
+    last_report: Option<Instant>,
+    reports: usize
+}
+
+impl<R: Read> ProgressReader<R> {
+    pub fn new(inner: R, name: String, total: Option<u64>) -> ProgressReader<R> {
+        ProgressReader { inner, name, total, read: 0, last_report: None, reports: 0 }
+    }
+
+    fn report(&mut self) {
+        let line = match self.total {
+            Some(total) if total > 0 => format!("{}: {:>3}% ({} of {} bytes)", self.name, 
+                min(self.read * 100 / total, 100), self.read, total),
+            _ => format!("{}: {} {} bytes", self.name, SPINNER[self.reports % SPINNER.len()], 
+                self.read)
+        };
+        let _ = write!(&mut io::stderr(), "\r{}", line);
+        self.reports += 1;
+        self.last_report = Some(Instant::now());
+    }
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.read += len as u64;
+        let due = self.last_report.map_or(true, |last| 
+            last.elapsed() >= Duration::from_millis(REPORT_INTERVAL_MS));
+        if due {
+            self.report();
+        }
+        Ok(len)
+    }
+}
+
+impl<R: Read> Drop for ProgressReader<R> {
+    fn drop(&mut self) {
+        if self.reports > 0 {
+            self.report();
+            let _ = writeln!(&mut io::stderr());
+        }
+    }
+}
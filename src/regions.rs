@@ -0,0 +1,395 @@
+//! Regions of the input in which patterns are left alone, e.g., LaTeX verbatim environments.
+//!
+//! Filters track these regions while the conversion streams the input. They see every byte of
+//! the input in order, including the text of matches, so their state never depends on how the
+//! input happens to be split into chunks.
+
+/// The marker that introduces directives like `% to-uni:off` unless configured otherwise.
+pub const DEFAULT_DIRECTIVE_MARKER: &'static str = "to-uni:";
+
+/// Tracks one kind of region in which patterns must not be replaced.
+pub trait RegionFilter {
+    /// Whether patterns are to be replaced at the current position.
+    fn active(&self) -> bool;
+
+    /// Advances over the next byte of the input.
+    fn feed(&mut self, byte: u8);
+
+    /// Called after the last byte of the input, e.g., to warn about unterminated regions.
+    fn finish(&mut self) {}
+}
+
+/// Which regions are excluded from the conversion.
+#[derive(Debug, Clone, Default)]
+pub struct RegionSettings {
+    /// The text preceding the directives `off`, `on` and `ignore-next-line` in the input 
+    /// (e.g., `to-uni:`). Directives are ignored if this is empty.
+    pub directive_marker: String,
+    /// Names of verbatim environments (e.g., `verbatim`). `\verb` is skipped whenever this is
+    /// not empty.
+    pub verbatim_environments: Vec<String>,
+    /// Whether LaTeX comments (from an unescaped `%` to the end of the line) are skipped.
+    pub comments: bool,
+    /// Whether patterns are only replaced in math mode.
+    pub math_only: bool
+}
+
+impl RegionSettings {
+    /// Fresh filters for a single input. Each filter only sees the bytes that the filters
+    /// before it consider active.
+    pub fn filters(&self) -> Vec<Box<RegionFilter>> {
+        let mut filters : Vec<Box<RegionFilter>> = Vec::new();
+        // Comes first, so directives are seen even where the other filters skip the text
+        if !self.directive_marker.is_empty() {
+            filters.push(Box::new(DirectiveFilter::new(&self.directive_marker)));
+        }
+        if !self.verbatim_environments.is_empty() {
+            filters.push(Box::new(VerbatimFilter::new(&self.verbatim_environments)));
+        }
+        // After the verbatim filter, since a `%` in a verbatim environment is no comment
+        if self.comments {
+            filters.push(Box::new(CommentFilter::default()));
+        }
+        if self.math_only {
+            filters.push(Box::new(MathFilter::default()));
+        }
+        filters
+    }
+}
+
+/// The last few bytes of the input, to recognize markers that span several chunks.
+struct Tail {
+    bytes: Vec<u8>,
+    capacity: usize
+}
+
+impl Tail {
+    fn new(capacity: usize) -> Tail {
+        Tail { bytes: Vec::with_capacity(capacity + 1), capacity }
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.bytes.len() == self.capacity {
+            self.bytes.remove(0);
+        }
+        self.bytes.push(byte);
+    }
+
+    fn ends_with(&self, marker: &[u8]) -> bool {
+        self.bytes.ends_with(marker)
+    }
+
+    fn clear(&mut self) {
+        self.bytes.clear();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VerbatimState {
+    Normal,
+    /// Inside the environment with the given index
+    Environment(usize),
+    /// After `\verb` (or `\verb*`), waiting for the delimiter
+    VerbStart,
+    /// Inside `\verb`, until the given delimiter
+    Verb(u8)
+}
+
+/// Skips `\begin{ENV}...\end{ENV}` for the configured environments as well as `\verb|...|`.
+/// Environments don't nest: everything up to the `\end` of the environment that started the
+/// region is taken literally, including `\begin` of other environments.
+struct VerbatimFilter {
+    /// `\begin{ENV}` and `\end{ENV}` for each environment
+    markers: Vec<(Vec<u8>, Vec<u8>)>,
+    state: VerbatimState,
+    tail: Tail
+}
+
+impl VerbatimFilter {
+    fn new(environments: &[String]) -> VerbatimFilter {
+        let markers : Vec<_> = environments.iter().map(|env| (
+            format!("\\begin{{{}}}", env).into_bytes(),
+            format!("\\end{{{}}}", env).into_bytes())).collect();
+        let longest = markers.iter().map(|&(ref begin, ref end)| begin.len().max(end.len()))
+            .max().unwrap_or(0).max(b"\\verb*".len());
+        VerbatimFilter { markers, state: VerbatimState::Normal, tail: Tail::new(longest) }
+    }
+}
+
+impl RegionFilter for VerbatimFilter {
+    fn active(&self) -> bool {
+        self.state == VerbatimState::Normal
+    }
+
+    fn feed(&mut self, byte: u8) {
+        self.tail.push(byte);
+        self.state = match self.state {
+            VerbatimState::Normal => {
+                if let Some(index) = self.markers.iter().position(|m| self.tail.ends_with(&m.0)) {
+                    debug!("Entering verbatim environment");
+                    self.tail.clear();
+                    VerbatimState::Environment(index)
+                } else if self.tail.ends_with(b"\\verb") {
+                    VerbatimState::VerbStart
+                } else {
+                    VerbatimState::Normal
+                }
+            },
+            VerbatimState::Environment(index) => {
+                if self.tail.ends_with(&self.markers[index].1) {
+                    debug!("Leaving verbatim environment");
+                    VerbatimState::Normal
+                } else {
+                    VerbatimState::Environment(index)
+                }
+            },
+            VerbatimState::VerbStart => match byte {
+                b'*' if self.tail.ends_with(b"\\verb*") => VerbatimState::VerbStart,
+                // Another command that merely starts with \verb (e.g., \verbatiminput)
+                b if b.is_ascii_alphabetic() => VerbatimState::Normal,
+                b'\n' => VerbatimState::Normal,
+                delimiter => VerbatimState::Verb(delimiter)
+            },
+            VerbatimState::Verb(delimiter) => {
+                // \verb cannot span lines, so a newline ends it as well
+                if byte == delimiter || byte == b'\n' {
+                    self.tail.clear();
+                    VerbatimState::Normal
+                } else {
+                    VerbatimState::Verb(delimiter)
+                }
+            }
+        };
+    }
+}
+
+/// Skips LaTeX comments: everything from an unescaped `%` up to the end of the line.
+#[derive(Default)]
+struct CommentFilter {
+    in_comment: bool,
+    /// Whether the previous byte is a backslash that escapes the next one
+    escaped: bool
+}
+
+impl RegionFilter for CommentFilter {
+    fn active(&self) -> bool {
+        !self.in_comment
+    }
+
+    fn feed(&mut self, byte: u8) {
+        if self.in_comment {
+            self.in_comment = byte != b'\n';
+        } else {
+            // `\%` is a literal percent sign but `\\%` is a line break followed by a comment
+            self.in_comment = byte == b'%' && !self.escaped;
+            self.escaped = byte == b'\\' && !self.escaped;
+        }
+    }
+}
+
+const MAX_DIRECTIVE_LINE: usize = 1024;
+
+/// Follows the directives `off`, `on` and `ignore-next-line` in the input, usually placed in 
+/// comments like `% to-uni:off`. A directive takes effect at the start of the next line.
+struct DirectiveFilter {
+    marker: Vec<u8>,
+    /// The current line up to the current position
+    line: Vec<u8>,
+    off: bool,
+    ignore_line: bool
+}
+
+impl DirectiveFilter {
+    fn new(marker: &str) -> DirectiveFilter {
+        DirectiveFilter { marker: marker.as_bytes().to_vec(), line: Vec::new(), off: false, 
+            ignore_line: false }
+    }
+
+    /// The directive in the current line, if any.
+    fn directive(&self) -> Option<&[u8]> {
+        let start = self.line.windows(self.marker.len()).position(|w| w == &self.marker[..])? 
+            + self.marker.len();
+        let rest = &self.line[start ..];
+        let end = rest.iter().position(|b| b.is_ascii_whitespace()).unwrap_or(rest.len());
+        Some(&rest[.. end])
+    }
+}
+
+impl RegionFilter for DirectiveFilter {
+    fn active(&self) -> bool {
+        !self.off && !self.ignore_line
+    }
+
+    fn feed(&mut self, byte: u8) {
+        if byte != b'\n' {
+            // Directives are in short lines; there is no need to hold on to long ones
+            if self.line.len() < MAX_DIRECTIVE_LINE {
+                self.line.push(byte);
+            }
+            return;
+        }
+
+        self.ignore_line = false;
+        match self.directive() {
+            Some(b"off") => {
+                debug!("Conversion turned off by directive");
+                self.off = true;
+            },
+            Some(b"on") => {
+                debug!("Conversion turned on by directive");
+                self.off = false;
+            },
+            Some(b"ignore-next-line") => self.ignore_line = true,
+            Some(b"") | None => (),
+            Some(other) => warn!("Ignoring unknown directive {:?}", String::from_utf8_lossy(other))
+        }
+        self.line.clear();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MathState {
+    Outside,
+    /// `$...$`
+    Inline,
+    /// `$$...$$`
+    Display,
+    /// `\(...\)`
+    Paren,
+    /// `\[...\]`
+    Bracket
+}
+
+impl MathState {
+    fn opening(&self) -> &'static str {
+        match *self {
+            MathState::Outside => "",
+            MathState::Inline => "$",
+            MathState::Display => "$$",
+            MathState::Paren => "\\(",
+            MathState::Bracket => "\\["
+        }
+    }
+}
+
+impl Default for MathState {
+    fn default() -> MathState {
+        MathState::Outside
+    }
+}
+
+/// Only lets patterns through in math mode: `$...$`, `$$...$$`, `\(...\)` and `\[...\]`.
+/// Unbalanced delimiters are reported and otherwise ignored. Since math mode cannot contain a 
+/// paragraph break, an empty line ends math mode as well (with a warning), so a stray `$` 
+/// doesn't affect the rest of the document.
+#[derive(Default)]
+struct MathFilter {
+    state: MathState,
+    /// Whether the previous byte is a `$` whose meaning depends on the next byte (`$` or `$$`)
+    dollar: bool,
+    /// Whether the previous byte is a backslash that escapes the next one
+    escaped: bool,
+    /// Whether the current line is empty so far (apart from whitespace)
+    blank: bool,
+    /// Zero-based line number, for messages
+    line: usize,
+    /// Line on which the current math region started
+    start_line: usize
+}
+
+impl MathFilter {
+    fn enter(&mut self, state: MathState) {
+        self.state = state;
+        self.start_line = self.line;
+    }
+
+    fn close(&mut self, state: MathState, closing: &str) {
+        if self.state == state {
+            self.state = MathState::Outside;
+        } else {
+            warn!("Ignoring unbalanced {} on line {}", closing, self.line + 1);
+        }
+    }
+
+    fn open(&mut self, state: MathState) {
+        if self.state == MathState::Outside {
+            self.enter(state);
+        } else {
+            warn!("Ignoring {} on line {} within {} from line {}", state.opening(), self.line + 1, 
+                self.state.opening(), self.start_line + 1);
+        }
+    }
+
+    /// Handles a single `$` that turned out not to be part of `$$`.
+    fn single_dollar(&mut self) {
+        match self.state {
+            MathState::Outside => self.enter(MathState::Inline),
+            MathState::Inline => self.state = MathState::Outside,
+            MathState::Display => warn!("Ignoring unbalanced $ on line {} within $$ from line {}", 
+                self.line + 1, self.start_line + 1),
+            _ => self.open(MathState::Inline)
+        }
+    }
+}
+
+impl RegionFilter for MathFilter {
+    fn active(&self) -> bool {
+        self.state != MathState::Outside || self.dollar
+    }
+
+    fn feed(&mut self, byte: u8) {
+        if self.dollar {
+            self.dollar = false;
+            if byte == b'$' {
+                match self.state {
+                    MathState::Display => self.state = MathState::Outside,
+                    _ => self.open(MathState::Display)
+                }
+                return;
+            }
+            self.single_dollar();
+        }
+
+        let escaped = self.escaped;
+        self.escaped = byte == b'\\' && !escaped;
+        match byte {
+            // `\$` is a literal dollar sign
+            b'$' if !escaped => {
+                if self.state == MathState::Inline {
+                    self.state = MathState::Outside;
+                } else {
+                    self.dollar = true;
+                }
+            },
+            b'(' if escaped => self.open(MathState::Paren),
+            b')' if escaped => self.close(MathState::Paren, "\\)"),
+            b'[' if escaped => self.open(MathState::Bracket),
+            b']' if escaped => self.close(MathState::Bracket, "\\]"),
+            b'\n' => {
+                if self.blank && self.state != MathState::Outside {
+                    warn!("Math mode {} from line {} ends at an empty line", self.state.opening(), 
+                        self.start_line + 1);
+                    self.state = MathState::Outside;
+                }
+                self.line += 1;
+                self.blank = true;
+                return;
+            },
+            _ => ()
+        }
+        if !byte.is_ascii_whitespace() {
+            self.blank = false;
+        }
+    }
+
+    fn finish(&mut self) {
+        if self.dollar {
+            self.dollar = false;
+            self.single_dollar();
+        }
+        if self.state != MathState::Outside {
+            warn!("Math mode {} from line {} is not terminated", self.state.opening(), 
+                self.start_line + 1);
+        }
+    }
+}
@@ -0,0 +1,61 @@
+//! Helpers shared by the unit tests.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Mutex, MutexGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use docopt::Docopt;
+
+use config::{Args, USAGE};
+
+static TEMP_DIR_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Serializes tests that change the environment or the current directory, which are shared by
+/// all threads of the test process.
+static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+pub fn lock_env() -> MutexGuard<'static, ()> {
+    ENV_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// A fresh directory below the system's temporary directory that is removed (along with its
+/// contents) when dropped.
+pub struct TempDir {
+    path: PathBuf
+}
+
+impl TempDir {
+    pub fn new() -> TempDir {
+        let path = env::temp_dir().join(format!("to-uni-test-{}-{}", process::id(),
+            TEMP_DIR_COUNT.fetch_add(1, Ordering::SeqCst)));
+        fs::create_dir_all(&path).expect("Cannot create temporary test directory");
+        TempDir { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Writes `contents` to the file `name` in this directory and returns its path.
+    pub fn write(&self, name: &str, contents: &[u8]) -> PathBuf {
+        let path = self.path.join(name);
+        fs::write(&path, contents).expect("Cannot write test file");
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Parses a command line (without the program name) the way the `to-uni` binary does.
+pub fn args(argv: &[&str]) -> Args {
+    Docopt::new(USAGE)
+        .and_then(|d| d.argv(Some("to-uni").into_iter().chain(argv.iter().cloned())).deserialize())
+        .unwrap_or_else(|e| panic!("Invalid test command line {:?}: {}", argv, e))
+}
@@ -0,0 +1,62 @@
+//! ASCII approximations of the replacements, e.g., for file names or legacy systems that can't 
+//! handle unicode.
+
+use std::collections::HashMap;
+
+use ::builtin;
+
+/// What to do with a character that has no ASCII approximation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissingTranslit {
+    /// Keep the character as it is
+    Pass,
+    /// Fail the conversion as soon as a replacement containing the character is needed
+    Error
+}
+
+impl MissingTranslit {
+    /// Parses the name used on the command line.
+    pub fn from_name(name: &str) -> Option<MissingTranslit> {
+        match name {
+            "pass" => Some(MissingTranslit::Pass),
+            "error" => Some(MissingTranslit::Error),
+            _ => None
+        }
+    }
+}
+
+/// Maps unicode text to ASCII. Entries from the `translit` section of the configuration file 
+/// take precedence over the built-in approximations (greek letter names and latin letters 
+/// without accents).
+#[derive(Debug, Clone)]
+pub struct Transliteration {
+    /// Unicode text → ASCII approximation, from the configuration file
+    pub table: HashMap<String, String>,
+    pub missing: MissingTranslit
+}
+
+impl Transliteration {
+    pub fn new(missing: MissingTranslit) -> Transliteration {
+        Transliteration { table: HashMap::new(), missing }
+    }
+
+    /// The ASCII approximation of `text`. A complete entry in the table wins, otherwise each 
+    /// character is approximated on its own. Fails with the first character that has no 
+    /// approximation (unless such characters are passed through).
+    pub fn apply(&self, text: &str) -> Result<String, char> {
+        if let Some(ascii) = self.table.get(text) {
+            return Ok(ascii.clone());
+        }
+        let mut result = String::with_capacity(text.len());
+        let mut buf = [0; 4];
+        for c in text.chars() {
+            let key : &str = c.encode_utf8(&mut buf);
+            match self.table.get(key).cloned().or_else(|| builtin::transliteration(c)) {
+                Some(ascii) => result.push_str(&ascii),
+                None if self.missing == MissingTranslit::Pass => result.push(c),
+                None => return Err(c)
+            }
+        }
+        Ok(result)
+    }
+}